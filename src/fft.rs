@@ -0,0 +1,113 @@
+use std::f64::consts::PI;
+use std::ops::{ Add, Sub, Mul };
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Complex {
+	pub re: f32,
+	pub im: f32
+}
+
+impl Complex {
+	pub fn new(re: f32, im: f32) -> Complex {
+		Complex { re, im }
+	}
+
+	pub fn zero() -> Complex {
+		Complex { re: 0.0, im: 0.0 }
+	}
+}
+
+impl Add for Complex {
+	type Output = Complex;
+
+	fn add(self, o: Complex) -> Complex {
+		Complex::new(self.re + o.re, self.im + o.im)
+	}
+}
+
+impl Sub for Complex {
+	type Output = Complex;
+
+	fn sub(self, o: Complex) -> Complex {
+		Complex::new(self.re - o.re, self.im - o.im)
+	}
+}
+
+impl Mul for Complex {
+	type Output = Complex;
+
+	fn mul(self, o: Complex) -> Complex {
+		Complex::new(
+			self.re * o.re - self.im * o.im,
+			self.re * o.im + self.im * o.re
+		)
+	}
+}
+
+/// In-place iterative radix-2 FFT of a fixed size, with the twiddle
+/// factors computed once up front. The inverse transform is scaled by `1/N`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Fft {
+	twiddles: Vec<Complex>
+}
+
+impl Fft {
+	/// `size` must be a power of two.
+	pub fn new(size: usize) -> Fft {
+		assert!(size.is_power_of_two(), "FFT size must be a power of two.");
+		let twiddles = (0..size / 2).map(|k| {
+			let a = -2.0 * PI * k as f64 / size as f64;
+			Complex::new(a.cos() as f32, a.sin() as f32)
+		}).collect();
+		Fft { twiddles }
+	}
+
+	pub fn size(&self) -> usize {
+		self.twiddles.len() * 2
+	}
+
+	pub fn process(&self, data: &mut [Complex], inverse: bool) {
+		let n = data.len();
+		assert_eq!(n, self.size(), "FFT input doesn't match the planned size.");
+
+		let mut j = 0;
+		for i in 1..n {
+			let mut bit = n >> 1;
+			while j & bit != 0 {
+				j ^= bit;
+				bit >>= 1;
+			}
+			j |= bit;
+			if i < j {
+				data.swap(i, j);
+			}
+		}
+
+		let mut len = 2;
+		while len <= n {
+			let half = len / 2;
+			let stride = n / len;
+			for start in (0..n).step_by(len) {
+				for k in 0..half {
+					let mut w = self.twiddles[k * stride];
+					if inverse {
+						w.im = -w.im;
+					}
+					let u = data[start + k];
+					let v = data[start + k + half] * w;
+					data[start + k] = u + v;
+					data[start + k + half] = u - v;
+				}
+			}
+			len <<= 1;
+		}
+
+		if inverse {
+			let scale = 1.0 / n as f32;
+			for c in data.iter_mut() {
+				c.re *= scale;
+				c.im *= scale;
+			}
+		}
+	}
+}
@@ -1,14 +1,23 @@
 extern crate sdl2;
 extern crate notify;
 
+mod fft;
+mod midi;
+mod monitor;
 mod node;
+mod osc;
 mod parser;
+mod wav;
 
 use self::node::{ NodeGraph, Input };
-use self::parser::{ Parser, GraphLoader };
+use self::parser::{ Parser, GraphLoader, ParseError };
+use self::midi::NoteEvent;
+use self::monitor::Monitor;
 
 use sdl2::pixels::Color;
 use sdl2::rect::Point;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::audio::{ AudioCallback, AudioSpecDesired };
@@ -17,9 +26,18 @@ use notify::{ Watcher, RecursiveMode, DebouncedEvent };
 use std::sync::mpsc;
 use std::sync::mpsc::{ Receiver, Sender };
 use std::time::Duration;
+use std::env;
+use std::process;
 use std::fs::File;
 use std::fs;
+use std::io;
+use std::io::Read;
 use std::path::Path;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+
+const WINDOW_WIDTH: u32 = 640;
+const WINDOW_HEIGHT: u32 = 480;
 
 struct AudioOutput {
 	rx: Receiver<Vec<f32>>,
@@ -31,18 +49,312 @@ impl AudioCallback for AudioOutput {
 
 	fn callback(&mut self, out: &mut [f32]) {
 		let data = self.rx.recv().unwrap();
-		out.copy_from_slice(&data);
+		let n = out.len().min(data.len());
+		out[..n].copy_from_slice(&data[..n]);
+		for s in out[n..].iter_mut() {
+			*s = 0.0;
+		}
 		self.cs.send(data).unwrap();
 	}
 }
 
+struct Options {
+	strict: bool,
+	profile: bool,
+	click: bool,
+	stdin: bool,
+	monitor: bool,
+	trigger: bool,
+	no_clip: bool,
+	builtins: bool,
+	check: Option<String>,
+	dump: Option<String>,
+	render: Option<String>,
+	ceiling: Option<f32>,
+	duration: f32,
+	max_nodes: Option<usize>,
+	rate: Option<u32>,
+	buffer: Option<usize>,
+	budget_us: Option<u64>,
+	midi: Option<String>,
+	midi_in: Option<String>,
+	osc_port: Option<u16>
+}
+
+// Accepts "-1dB", "-1db" or a bare "-1".
+fn parse_db(s: &str) -> Option<f32> {
+	let s = s.trim();
+	let s = s.strip_suffix("dB").or_else(|| s.strip_suffix("db")).unwrap_or(s);
+	s.parse().ok()
+}
+
+impl Options {
+	fn from_args() -> Options {
+		let args: Vec<String> = env::args().collect();
+		Options::parse(&args)
+	}
+
+	fn parse(args: &[String]) -> Options {
+		let flag = |name: &str| args.iter().any(|a| a == name);
+		let value = |name: &str| {
+			args.iter().position(|a| a == name).and_then(|i| args.get(i + 1)).cloned()
+		};
+		Options {
+			strict: flag("--strict"),
+			profile: flag("--profile"),
+			click: flag("--click"),
+			stdin: flag("-") || flag("--stdin"),
+			monitor: flag("--monitor"),
+			trigger: flag("--trigger"),
+			no_clip: flag("--no-clip"),
+			builtins: flag("--builtins"),
+			check: value("--check"),
+			dump: value("--dump"),
+			render: value("--render"),
+			ceiling: value("--ceiling").and_then(|v| parse_db(&v)),
+			duration: value("--duration").and_then(|v| v.parse().ok()).unwrap_or(5.0),
+			max_nodes: value("--max-nodes").and_then(|v| v.parse().ok()),
+			rate: value("--rate").and_then(|v| v.parse().ok()),
+			buffer: value("--buffer").and_then(|v| v.parse().ok()),
+			budget_us: value("--budget-us").and_then(|v| v.parse().ok()),
+			midi: value("--midi"),
+			midi_in: value("--midi-in"),
+			osc_port: value("--osc").and_then(|v| v.parse().ok())
+		}
+	}
+}
+
+fn configure_loader(loader: &mut GraphLoader, opts: &Options) {
+	loader.set_strict(opts.strict);
+	if let Some(limit) = opts.max_nodes {
+		loader.set_max_nodes(limit);
+	}
+	if let Some(rate) = opts.rate {
+		loader.set_sample_rate(rate);
+	}
+	if let Some(frames) = opts.buffer {
+		loader.set_buffer_size(frames);
+	}
+}
+
+fn check_patch(file: &str, opts: &Options) -> Result<NodeGraph, ParseError> {
+	let mut loader = GraphLoader::new(file)?;
+	configure_loader(&mut loader, opts);
+	loader.load()
+}
+
+fn read_patch<R: Read>(mut reader: R) -> Result<String, String> {
+	let mut source = String::new();
+	reader.read_to_string(&mut source).map_err(|e| format!("Failed to read patch: {}", e))?;
+	Ok(source)
+}
+
+fn load_graph(mut loader: GraphLoader, opts: &Options, notes: &[NoteEvent]) -> Result<NodeGraph, ParseError> {
+	configure_loader(&mut loader, opts);
+	let (graph, errors) = loader.load_lenient()?;
+	for e in errors.iter() {
+		println!("Error: {}", e);
+	}
+	Ok(prepare_graph(graph, &loader, opts, notes))
+}
+
+// Offline renders fail on any error instead of silencing the broken call.
+fn load_render_graph(mut loader: GraphLoader, opts: &Options, notes: &[NoteEvent]) -> Result<NodeGraph, ParseError> {
+	configure_loader(&mut loader, opts);
+	let graph = loader.load()?;
+	Ok(prepare_graph(graph, &loader, opts, notes))
+}
+
+fn prepare_graph(mut graph: NodeGraph, loader: &GraphLoader, opts: &Options, notes: &[NoteEvent]) -> NodeGraph {
+	graph.set_profiling(opts.profile);
+	graph.set_click(opts.click);
+	graph.set_master_clip(!opts.no_clip);
+	if !notes.is_empty() {
+		match (loader.store_id("midi_freq"), loader.store_id("midi_gate")) {
+			(Some(freq), Some(gate)) => midi::schedule_notes(&mut graph, notes, freq, gate),
+			_ => println!("Warning: MIDI playback needs \"midi_freq\" and \"midi_gate\" stores.")
+		}
+	}
+	if let Some(us) = opts.budget_us {
+		graph.set_sample_budget(Duration::from_micros(us));
+	}
+	graph
+}
+
+fn load_file(path: &Path, opts: &Options, notes: &[NoteEvent]) -> Result<NodeGraph, ParseError> {
+	load_graph(GraphLoader::new(path.to_str().unwrap())?, opts, notes)
+}
+
+// Fills `block` with interleaved frames for a mono or stereo device.
+fn render_block(graph: &mut NodeGraph, block: &mut [f32], channels: usize) {
+	if channels == 2 {
+		for frame in block.chunks_exact_mut(2) {
+			let (left, right) = graph.sample_stereo();
+			frame[0] = left;
+			frame[1] = right;
+		}
+	} else {
+		for s in block.iter_mut() {
+			*s = graph.sample();
+		}
+	}
+}
+
+// Plots `samples` across the window, full scale filling the height. With
+// `trigger` set, drawing starts at the first rising zero crossing in the
+// first half of the block so a steady wave doesn't scroll between frames.
+fn draw_waveform(canvas: &mut Canvas<Window>, samples: &[f32], trigger: bool, width: u32, height: u32) {
+	let (start, count) = match trigger {
+		true => {
+			let half = samples.len() / 2;
+			let start = (1..half)
+				.find(|&i| samples[i - 1] <= 0.0 && samples[i] > 0.0)
+				.unwrap_or(0);
+			(start, samples.len() - half)
+		},
+		false => (0, samples.len())
+	};
+	if count == 0 || width == 0 {
+		return;
+	}
+
+	let mid = height as f32 / 2.0;
+	let points: Vec<Point> = (0..width)
+		.map(|x| {
+			let s = samples[start + x as usize * count / width as usize];
+			let y = (mid - s * mid).max(0.0).min(height as f32 - 1.0);
+			Point::new(x as i32, y as i32)
+		})
+		.collect();
+	if let Err(e) = canvas.draw_lines(points.as_slice()) {
+		println!("Error: {}", e);
+	}
+}
+
+fn content_hash(path: &Path) -> Option<u64> {
+	let data = fs::read(path).ok()?;
+	let mut hasher = DefaultHasher::new();
+	data.hash(&mut hasher);
+	Some(hasher.finish())
+}
+
 fn main() {
+	let mut opts = Options::from_args();
+
+	if opts.builtins {
+		for name in parser::builtin_names() {
+			println!("{}", name);
+		}
+		return;
+	}
+
+	let notes = match &opts.midi {
+		Some(file) => match midi::load_file(file) {
+			Ok(notes) => notes,
+			Err(e) => {
+				println!("{}", e);
+				process::exit(1);
+			}
+		},
+		None => Vec::new()
+	};
+
+	if let Some(file) = &opts.dump {
+		match check_patch(file, &opts) {
+			Ok(graph) => {
+				print!("{}", graph.to_source());
+				return;
+			},
+			Err(e) => {
+				println!("{}: {}", file, e);
+				process::exit(1);
+			}
+		}
+	}
+
+	if let Some(file) = &opts.check {
+		match check_patch(file, &opts) {
+			Ok(_) => {
+				println!("{}: OK", file);
+				return;
+			},
+			Err(e) => {
+				println!("{}: {}", file, e);
+				process::exit(1);
+			}
+		}
+	}
+
+	let stdin_source = match opts.stdin {
+		true => match read_patch(io::stdin()) {
+			Ok(source) => source,
+			Err(e) => {
+				println!("{}", e);
+				process::exit(1);
+			}
+		},
+		false => String::new()
+	};
+
+	// Synth file
+	let path = Path::new("synth.twg");
+
+	if let Some(out) = &opts.render {
+		let loaded = match opts.stdin {
+			true => load_render_graph(GraphLoader::from_source(&stdin_source), &opts, &notes),
+			false => GraphLoader::new(path.to_str().unwrap()).and_then(|l| load_render_graph(l, &opts, &notes))
+		};
+		let mut graph = match loaded {
+			Ok(graph) => graph,
+			Err(e) => {
+				println!("Error: {}", e);
+				process::exit(1);
+			}
+		};
+		let rate = graph.sample_rate();
+		if let Err(e) = wav::render_to_wav(&mut graph, opts.duration, rate, Path::new(out), opts.ceiling) {
+			println!("{}", e);
+			process::exit(1);
+		}
+		println!("Rendered {} s to {}", opts.duration, out);
+		return;
+	}
+
+	let mut graph;
+	let mut last_hash = None;
+	if opts.stdin {
+		// Piped patches are loaded once and never watched.
+		graph = match load_graph(GraphLoader::from_source(&stdin_source), &opts, &notes) {
+			Ok(graph) => graph,
+			Err(e) => {
+				println!("Error: {}", e);
+				process::exit(1);
+			}
+		};
+	} else {
+		if !path.exists() {
+			fs::write(path, "Output(0.0)").expect("Failed to write to file.");
+		}
+		// A broken patch plays silence until it's fixed.
+		let hash = content_hash(path);
+		graph = match load_file(path, &opts, &notes) {
+			Ok(g) => {
+				last_hash = hash;
+				g
+			},
+			Err(e) => {
+				println!("Error: {}", e);
+				NodeGraph::new(opts.rate.unwrap_or(44100))
+			}
+		};
+	}
+
 	let sdl = sdl2::init().unwrap();
 	let video = sdl.video().unwrap();
 	let audio = sdl.audio().unwrap();
 
 	let window = video
-		.window("Twen", 640, 480)
+		.window("Twen", WINDOW_WIDTH, WINDOW_HEIGHT)
 		.position_centered()
 		.set_window_flags(sdl2::sys::SDL_WindowFlags::SDL_WINDOW_ALWAYS_ON_TOP as u32)
 		.build()
@@ -50,9 +362,9 @@ fn main() {
 	let mut canvas = window.into_canvas().build().unwrap();
 
 	let desired_spec = AudioSpecDesired {
-		freq: Some(44100),
-		channels: Some(1),
-		samples: Some(1024)
+		freq: Some(graph.sample_rate() as i32),
+		channels: Some(2),
+		samples: Some(graph.buffer_size().min(u16::MAX as usize) as u16)
 	};
 
 	let (audioSender, rx) = mpsc::channel();
@@ -63,41 +375,101 @@ fn main() {
 		}
 	}).unwrap();
 	device.resume();
+	let channels = device.spec().channels as usize;
+	let buffer = device.spec().samples as usize;
 
-	// Synth file
-	let path = Path::new("synth.twg");
-	if !path.exists() {
-		fs::write(path, "Output(0.0)").expect("Failed to write to file.");
+	// Reloads build at whatever rate the device actually runs at.
+	let rate = device.spec().freq as u32;
+	opts.rate = Some(rate);
+	if rate != graph.sample_rate() {
+		println!("Warning: Audio device runs at {} Hz, reloading the patch at that rate.", rate);
+		graph = match opts.stdin {
+			true => load_graph(GraphLoader::from_source(&stdin_source), &opts, &notes),
+			false => load_file(path, &opts, &notes)
+		}.unwrap_or_else(|_| NodeGraph::new(rate));
 	}
 
-	// Node graph
-	let mut loader = GraphLoader::new(path.to_str().unwrap());
-	let mut graph = loader.load();
-
-	// File changes listener
 	let (tx, rx) = mpsc::channel();
-	notify::watcher(tx, Duration::from_millis(1000))
-			.expect("Failed to watch file.")
-			.watch(path, RecursiveMode::NonRecursive).unwrap();
-
-	let mut init_samples = Vec::new();
-	for _ in 0..1024 {
-		init_samples.push(graph.sample());
+	if !opts.stdin {
+		// File changes listener
+		notify::watcher(tx, Duration::from_millis(1000))
+				.expect("Failed to watch file.")
+				.watch(path, RecursiveMode::NonRecursive).unwrap();
 	}
+
+	// OSC control
+	let osc_rx = match opts.osc_port {
+		Some(port) => match osc::spawn_server(port) {
+			Ok(rx) => Some(rx),
+			Err(e) => {
+				println!("{}", e);
+				None
+			}
+		},
+		None => None
+	};
+
+	// Live MIDI keyboard
+	let midi_rx = match &opts.midi_in {
+		Some(device) => match midi::spawn_input(device) {
+			Ok(rx) => Some(rx),
+			Err(e) => {
+				println!("{}", e);
+				None
+			}
+		},
+		None => None
+	};
+
+	let mut init_samples = vec![0.0; buffer * channels];
+	render_block(&mut graph, &mut init_samples, channels);
 	audioSender.send(init_samples).unwrap();
 
+	let mut monitor = Monitor::new(rate as usize * channels);
+
 	let mut event_pump = sdl.event_pump().unwrap();
 	'running: loop {
+		if let Some(osc_rx) = &osc_rx {
+			for (name, value) in osc_rx.try_iter() {
+				if osc::apply(&mut graph, &name, value).is_none() {
+					println!("Warning: Unknown store \"{}\".", name);
+				}
+			}
+		}
+
+		if let Some(midi_rx) = &midi_rx {
+			for (note, velocity) in midi_rx.try_iter() {
+				graph.midi_mut().note(note, velocity);
+			}
+		}
+
 		for event in rx.try_iter() {
 			match event {
 				DebouncedEvent::NoticeRemove(_) => {
 					fs::write(path, "Output(0.0)").expect("Failed to write to file.");
-					let mut loader = GraphLoader::new(path.to_str().unwrap());
-					graph = loader.load();
+					let hash = content_hash(path);
+					match load_file(path, &opts, &notes) {
+						Ok(g) => {
+							graph = g;
+							last_hash = hash;
+						},
+						Err(e) => println!("Error: {}", e)
+					}
 				},
 				DebouncedEvent::NoticeWrite(_) => {
-					let mut loader = GraphLoader::new(path.to_str().unwrap());
-					graph = loader.load();
+					let hash = content_hash(path);
+					if hash.is_some() && hash == last_hash {
+						continue;
+					}
+					// Keep playing the previous graph if the new one is broken.
+					match load_file(path, &opts, &notes) {
+						Ok(mut g) => {
+							g.migrate_from(&graph);
+							graph = g;
+							last_hash = hash;
+						},
+						Err(e) => println!("Error: {}", e)
+					}
 				},
 				_ => {}
 			}
@@ -114,31 +486,103 @@ fn main() {
 		}
 
 		let mut samples = audioReceiver.recv().unwrap();
-		for i in 0..1024 {
-			samples[i] = graph.sample();
-		}
+		render_block(&mut graph, &mut samples, channels);
 		audioSender.send(samples.clone()).unwrap();
 
+		if opts.monitor {
+			if graph.take_nan_frames() > 0 {
+				monitor.flag_nan();
+			}
+			if let Some(stats) = monitor.push(&samples) {
+				println!(
+					"peak {:.3}  rms {:.3}  dc {:+.4}{}{}",
+					stats.peak, stats.rms, stats.dc,
+					if stats.clipped { "  CLIP" } else { "" },
+					if stats.nan { "  NaN" } else { "" }
+				);
+			}
+		}
+
 		canvas.set_draw_color(Color::RGB(0, 0, 0));
 		canvas.clear();
 
 		canvas.set_draw_color(Color::RGB(0, 200, 55));
 
-		let mut px = 0;
-		let mut py = 240;
-		let step = 640.0 / 512.0;
-		for i in (0..640).step_by(step as usize) {
-			let s = (samples[640 - i] * 400.0) as i32;
-			let y = 240 - s;
+		let left: Vec<f32> = samples.iter().step_by(channels).cloned().collect();
+		draw_waveform(&mut canvas, &left, opts.trigger, WINDOW_WIDTH, WINDOW_HEIGHT);
 
-			canvas.draw_line(Point::new(px, py), Point::new(i as i32, y));
+		canvas.present();
+	}
 
-			py = y;
-			px = i as i32;
+	if opts.profile {
+		println!("Profile:");
+		for (name, time) in graph.profile_report().iter().take(10) {
+			println!("  {:<16} {:?}", name, time);
 		}
+	}
 
-		canvas.present();
+	if opts.budget_us.is_some() {
+		println!("Samples over budget: {}", graph.budget_overruns());
 	}
 
 	println!("Bye!");
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn content_hash_ignores_no_op_saves() {
+		let dir = env::temp_dir();
+		let a = dir.join(format!("twen-hash-a-{}.twg", process::id()));
+		let b = dir.join(format!("twen-hash-b-{}.twg", process::id()));
+		fs::write(&a, "Output(Sine(440, 0.5))").unwrap();
+		fs::write(&b, "Output(Sine(440, 0.5))").unwrap();
+		let before = content_hash(&a);
+		assert!(before.is_some());
+		assert_eq!(content_hash(&b), before);
+
+		fs::write(&a, "Output(Sine(440, 0.5))").unwrap();
+		assert_eq!(content_hash(&a), before);
+		fs::write(&a, "Output(Sine(220, 0.5))").unwrap();
+		assert_ne!(content_hash(&a), before);
+
+		fs::remove_file(&a).unwrap();
+		fs::remove_file(&b).unwrap();
+		assert_eq!(content_hash(&a), None);
+	}
+
+	#[test]
+	fn check_accepts_good_patches_and_rejects_bad_ones() {
+		let dir = env::temp_dir();
+		let good = dir.join(format!("twen-check-good-{}.twg", process::id()));
+		let bad = dir.join(format!("twen-check-bad-{}.twg", process::id()));
+		fs::write(&good, "Output(Sine(440, 0.5))").unwrap();
+		fs::write(&bad, "Output(Sine(440, 0.5)").unwrap();
+		let opts = Options::parse(&[]);
+
+		assert!(check_patch(good.to_str().unwrap(), &opts).is_ok());
+		let e = match check_patch(bad.to_str().unwrap(), &opts) {
+			Ok(_) => panic!("unbalanced patch passed --check"),
+			Err(e) => e
+		};
+		assert!(e.message.contains("end of input"), "{}", e);
+		assert!(check_patch("twen-missing.twg", &opts).is_err());
+
+		fs::remove_file(&good).unwrap();
+		fs::remove_file(&bad).unwrap();
+	}
+
+	#[test]
+	fn piped_patch_loads_into_a_playing_graph() {
+		let piped: &[u8] = b"Output(Sine(441, 0.5))\n";
+		let source = read_patch(piped).unwrap();
+		let mut graph = load_graph(GraphLoader::from_source(&source), &Options::parse(&[]), &[]).unwrap();
+		let peak = (0..100).map(|_| graph.sample().abs()).fold(0.0, f32::max);
+		assert!((peak - 0.5).abs() < 1e-3, "peak {}", peak);
+
+		let piped: &[u8] = &[0xFF, 0xFE];
+		assert!(read_patch(piped).is_err());
+	}
+}
@@ -0,0 +1,368 @@
+use std::fs;
+use std::io::Read;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use crate::node::NodeGraph;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct NoteEvent {
+	pub time: f64,
+	pub note: u8,
+	pub velocity: u8
+}
+
+impl NoteEvent {
+	pub fn is_on(&self) -> bool {
+		self.velocity > 0
+	}
+}
+
+enum Event {
+	Tempo(u32),
+	Note(u8, u8)
+}
+
+pub fn note_to_freq(note: u8) -> f32 {
+	440.0 * 2.0f32.powf((f32::from(note) - 69.0) / 12.0)
+}
+
+/// Live note state read by the `MidiFreq` and `MidiGate` nodes. With a
+/// single voice the most recently pressed key wins and releasing it falls
+/// back to the previous held key. With more voices each new key takes a free
+/// voice, stealing the one holding the oldest key when all are busy. A
+/// voice keeps its frequency after release so tails decay at the right pitch.
+#[derive(PartialEq, Debug, Clone)]
+pub struct MidiState {
+	held: Vec<u8>,
+	slots: Vec<Option<u8>>,
+	freqs: Vec<f32>
+}
+
+impl MidiState {
+	pub fn new() -> MidiState {
+		MidiState { held: Vec::new(), slots: vec![None], freqs: vec![note_to_freq(69)] }
+	}
+
+	/// Sets the number of voices, releasing every note.
+	pub fn set_voices(&mut self, count: usize) {
+		let count = count.max(1);
+		self.held.clear();
+		self.slots = vec![None; count];
+		self.freqs.resize(count, note_to_freq(69));
+	}
+
+	pub fn voices(&self) -> usize {
+		self.slots.len()
+	}
+
+	/// Applies a note event; a velocity of 0 is a note-off.
+	pub fn note(&mut self, note: u8, velocity: u8) {
+		self.held.retain(|n| *n != note);
+		if velocity > 0 {
+			self.held.push(note);
+		}
+
+		if self.slots.len() == 1 {
+			self.slots[0] = self.held.last().cloned();
+		} else if velocity == 0 {
+			for slot in self.slots.iter_mut().filter(|s| **s == Some(note)) {
+				*slot = None;
+			}
+			return;
+		} else {
+			let slots = &self.slots;
+			let voice = slots.iter().position(|s| *s == Some(note))
+				.or_else(|| slots.iter().position(|s| s.is_none()))
+				.or_else(|| self.held.iter().filter_map(|n| slots.iter().position(|s| *s == Some(*n))).next())
+				.unwrap_or(0);
+			self.slots[voice] = Some(note);
+		}
+
+		for (slot, freq) in self.slots.iter().zip(self.freqs.iter_mut()) {
+			if let Some(n) = slot {
+				*freq = note_to_freq(*n);
+			}
+		}
+	}
+
+	pub fn freq(&self, voice: usize) -> f32 {
+		self.freqs.get(voice).cloned().unwrap_or(0.0)
+	}
+
+	pub fn gate(&self, voice: usize) -> f32 {
+		match self.slots.get(voice) {
+			Some(Some(_)) => 1.0,
+			_ => 0.0
+		}
+	}
+}
+
+/// Turns a raw MIDI byte stream into note events, handling running status
+/// and skipping everything that isn't a note on or off.
+struct StreamParser {
+	status: u8,
+	data: Vec<u8>
+}
+
+impl StreamParser {
+	fn push(&mut self, b: u8) -> Option<(u8, u8)> {
+		if b >= 0xF8 {
+			return None;
+		}
+		if b & 0x80 != 0 {
+			self.status = b;
+			self.data.clear();
+			return None;
+		}
+
+		let len = match self.status & 0xF0 {
+			0x80 | 0x90 | 0xA0 | 0xB0 | 0xE0 => 2,
+			0xC0 | 0xD0 => 1,
+			_ => return None
+		};
+		self.data.push(b);
+		if self.data.len() < len {
+			return None;
+		}
+
+		let (note, velocity) = (self.data[0], *self.data.last().unwrap());
+		self.data.clear();
+		match self.status & 0xF0 {
+			0x80 => Some((note, 0)),
+			0x90 => Some((note, velocity)),
+			_ => None
+		}
+	}
+}
+
+/// Reads a raw MIDI device (e.g. `/dev/snd/midiC1D0`), forwarding
+/// `(note, velocity)` pairs.
+pub fn spawn_input(device: &str) -> Result<Receiver<(u8, u8)>, String> {
+	let mut file = fs::File::open(device).map_err(|e| format!("{}: {}", device, e))?;
+	let (tx, rx) = mpsc::channel();
+	thread::spawn(move || {
+		let mut parser = StreamParser { status: 0, data: Vec::new() };
+		let mut buf = [0u8; 64];
+		while let Ok(len) = file.read(&mut buf) {
+			if len == 0 {
+				break;
+			}
+			for b in buf[..len].iter() {
+				if let Some(ev) = parser.push(*b) {
+					if tx.send(ev).is_err() {
+						return;
+					}
+				}
+			}
+		}
+	});
+	Ok(rx)
+}
+
+fn byte(data: &[u8], pos: usize) -> Result<u8, String> {
+	match data.get(pos) {
+		Some(b) => Ok(*b),
+		None => Err("Unexpected end of MIDI data.".to_owned())
+	}
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, String> {
+	let mut v = 0;
+	for i in 0..4 {
+		v = (v << 8) | u32::from(byte(data, pos + i)?);
+	}
+	Ok(v)
+}
+
+fn read_vlq(data: &[u8], pos: &mut usize) -> Result<u32, String> {
+	let mut v = 0u32;
+	for _ in 0..4 {
+		let b = byte(data, *pos)?;
+		*pos += 1;
+		v = (v << 7) | u32::from(b & 0x7F);
+		if b & 0x80 == 0 {
+			return Ok(v);
+		}
+	}
+	Err("Invalid variable-length quantity.".to_owned())
+}
+
+fn parse_track(data: &[u8], events: &mut Vec<(u64, Event)>) -> Result<(), String> {
+	let mut pos = 0;
+	let mut tick = 0u64;
+	let mut status = 0u8;
+	while pos < data.len() {
+		tick += u64::from(read_vlq(data, &mut pos)?);
+
+		let b = byte(data, pos)?;
+		if b & 0x80 != 0 {
+			status = b;
+			pos += 1;
+		} else if status == 0 {
+			return Err("Running status without a previous status byte.".to_owned());
+		}
+
+		match status & 0xF0 {
+			0x80 => {
+				events.push((tick, Event::Note(byte(data, pos)?, 0)));
+				pos += 2;
+			},
+			0x90 => {
+				events.push((tick, Event::Note(byte(data, pos)?, byte(data, pos + 1)?)));
+				pos += 2;
+			},
+			0xA0 | 0xB0 | 0xE0 => pos += 2,
+			0xC0 | 0xD0 => pos += 1,
+			_ => match status {
+				0xF0 | 0xF7 => {
+					let len = read_vlq(data, &mut pos)? as usize;
+					pos += len;
+				},
+				0xFF => {
+					let kind = byte(data, pos)?;
+					pos += 1;
+					let len = read_vlq(data, &mut pos)? as usize;
+					if kind == 0x51 && len == 3 {
+						let tempo = (u32::from(byte(data, pos)?) << 16)
+							| (u32::from(byte(data, pos + 1)?) << 8)
+							| u32::from(byte(data, pos + 2)?);
+						events.push((tick, Event::Tempo(tempo)));
+					} else if kind == 0x2F {
+						break;
+					}
+					pos += len;
+				},
+				_ => return Err(format!("Invalid MIDI status byte 0x{:02X}.", status))
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Parses a standard MIDI file into note events timed in seconds. All tracks
+/// and channels are merged.
+pub fn parse(data: &[u8]) -> Result<Vec<NoteEvent>, String> {
+	if data.len() < 14 || &data[0..4] != b"MThd" {
+		return Err("Not a MIDI file.".to_owned());
+	}
+	let header_len = read_u32(data, 4)? as usize;
+	let division = (u32::from(byte(data, 12)?) << 8) | u32::from(byte(data, 13)?);
+	if division & 0x8000 != 0 {
+		return Err("SMPTE time division isn't supported.".to_owned());
+	}
+	if division == 0 {
+		return Err("Invalid time division.".to_owned());
+	}
+
+	let mut events = Vec::new();
+	let mut pos = 8 + header_len;
+	while pos + 8 <= data.len() {
+		let len = read_u32(data, pos + 4)? as usize;
+		let end = (pos + 8 + len).min(data.len());
+		if &data[pos..pos + 4] == b"MTrk" {
+			parse_track(&data[pos + 8..end], &mut events)?;
+		}
+		pos = end;
+	}
+	events.sort_by_key(|e| e.0);
+
+	let mut notes = Vec::new();
+	let mut tempo = 500_000.0;
+	let (mut last_tick, mut time) = (0u64, 0.0f64);
+	for (tick, event) in events.into_iter() {
+		time += (tick - last_tick) as f64 * tempo / (f64::from(division) * 1_000_000.0);
+		last_tick = tick;
+		match event {
+			Event::Tempo(t) => tempo = f64::from(t),
+			Event::Note(note, velocity) => notes.push(NoteEvent { time, note, velocity })
+		}
+	}
+	Ok(notes)
+}
+
+pub fn load_file(path: &str) -> Result<Vec<NoteEvent>, String> {
+	let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+	parse(&data).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Schedules note frequency and gate writes into two stores. The most recent
+/// note wins; releasing it closes the gate.
+pub fn schedule_notes(graph: &mut NodeGraph, notes: &[NoteEvent], freq_store: usize, gate_store: usize) {
+	let rate = f64::from(graph.sample_rate());
+	let mut current = None;
+	for ev in notes.iter() {
+		let at = (ev.time * rate).round() as u64;
+		if ev.is_on() {
+			graph.schedule_store(at, freq_store, note_to_freq(ev.note));
+			graph.schedule_store(at, gate_store, 1.0);
+			current = Some(ev.note);
+		} else if current == Some(ev.note) {
+			graph.schedule_store(at, gate_store, 0.0);
+			current = None;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::node::Input;
+
+	// One track at 96 ticks per beat and 120 BPM: A4 for half a second,
+	// a quarter second of rest, then C5 for a second, ended by a
+	// running-status note-on with zero velocity.
+	const SONG: &[u8] = &[
+		b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+		b'M', b'T', b'r', b'k', 0, 0, 0, 27,
+		0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+		0x00, 0x90, 69, 100,
+		0x60, 0x80, 69, 0,
+		0x30, 0x90, 72, 100,
+		0x81, 0x40, 72, 0,
+		0x00, 0xFF, 0x2F, 0x00
+	];
+
+	#[test]
+	fn song_notes_land_on_the_right_samples() {
+		let notes = parse(SONG).unwrap();
+		let times: Vec<(f64, u8, bool)> = notes.iter().map(|n| (n.time, n.note, n.is_on())).collect();
+		assert_eq!(times, vec![(0.0, 69, true), (0.5, 69, false), (0.75, 72, true), (1.75, 72, false)]);
+
+		let mut graph = NodeGraph::new(44100);
+		graph.set_master_clip(false);
+		let freq = graph.create_value_store();
+		let gate = graph.create_value_store();
+		let out = graph.create_mul(Input::Store(freq), Input::Store(gate));
+		graph.create_output(Input::Node(out)).unwrap();
+		schedule_notes(&mut graph, &notes, freq, gate);
+
+		let out: Vec<f32> = (0..88200).map(|_| graph.sample()).collect();
+		assert_eq!((out[0], out[22049], out[22050]), (440.0, 440.0, 0.0));
+		assert_eq!((out[33074], out[33075]), (0.0, note_to_freq(72)));
+		assert_eq!((out[77174], out[77175]), (note_to_freq(72), 0.0));
+	}
+
+	#[test]
+	fn note_numbers_convert_to_hz() {
+		assert_eq!(note_to_freq(69), 440.0);
+		assert_eq!(note_to_freq(81), 880.0);
+		assert_eq!(note_to_freq(57), 220.0);
+		assert!((note_to_freq(60) - 261.6256).abs() < 1e-3);
+		assert!((note_to_freq(0) - 8.175_799).abs() < 1e-4);
+	}
+
+	#[test]
+	fn last_pressed_key_drives_freq_and_gate() {
+		let mut state = MidiState::new();
+		assert_eq!(state.gate(0), 0.0);
+		state.note(60, 100);
+		state.note(64, 100);
+		assert_eq!((state.freq(0), state.gate(0)), (note_to_freq(64), 1.0));
+		state.note(64, 0);
+		assert_eq!((state.freq(0), state.gate(0)), (note_to_freq(60), 1.0));
+		state.note(60, 0);
+		assert_eq!((state.freq(0), state.gate(0)), (note_to_freq(60), 0.0));
+	}
+}
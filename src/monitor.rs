@@ -0,0 +1,102 @@
+/// Output statistics over one monitoring window.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Stats {
+	pub peak: f32,
+	pub rms: f32,
+	pub dc: f32,
+	pub nan: bool,
+	pub clipped: bool
+}
+
+/// Accumulates output blocks and reports `Stats` every `window` samples.
+/// NaN samples are flagged but left out of the other figures.
+pub struct Monitor {
+	window: usize,
+	count: usize,
+	finite: usize,
+	sum: f64,
+	sum_sq: f64,
+	peak: f32,
+	nan: bool,
+	clipped: bool
+}
+
+impl Monitor {
+	pub fn new(window: usize) -> Monitor {
+		Monitor {
+			window: window.max(1),
+			count: 0,
+			finite: 0,
+			sum: 0.0,
+			sum_sq: 0.0,
+			peak: 0.0,
+			nan: false,
+			clipped: false
+		}
+	}
+
+	/// Flags NaNs that were removed from the output before it reached
+	/// `push`, such as those silenced by the master clip.
+	pub fn flag_nan(&mut self) {
+		self.nan = true;
+	}
+
+	pub fn push(&mut self, block: &[f32]) -> Option<Stats> {
+		for &s in block.iter() {
+			self.count += 1;
+			if s.is_nan() {
+				self.nan = true;
+				continue;
+			}
+			self.finite += 1;
+			self.sum += f64::from(s);
+			self.sum_sq += f64::from(s) * f64::from(s);
+			self.peak = self.peak.max(s.abs());
+			self.clipped |= s.abs() >= 1.0;
+		}
+		if self.count < self.window {
+			return None;
+		}
+
+		let n = self.finite.max(1) as f64;
+		let stats = Stats {
+			peak: self.peak,
+			rms: (self.sum_sq / n).sqrt() as f32,
+			dc: (self.sum / n) as f32,
+			nan: self.nan,
+			clipped: self.clipped
+		};
+		*self = Monitor::new(self.window);
+		Some(stats)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn flagged_nans_are_reported() {
+		let mut monitor = Monitor::new(4);
+		monitor.flag_nan();
+		let stats = monitor.push(&[0.0; 4]).unwrap();
+		assert!(stats.nan);
+		assert_eq!(stats.peak, 0.0);
+		assert!(!monitor.push(&[0.0; 4]).unwrap().nan);
+	}
+
+	#[test]
+	fn window_reports_peak_rms_and_dc() {
+		let mut monitor = Monitor::new(8);
+		assert_eq!(monitor.push(&[0.5, -0.5, 0.5, -0.5]), None);
+		let stats = monitor.push(&[1.0, 0.0, 1.0, 0.0]).unwrap();
+		assert_eq!(stats.peak, 1.0);
+		assert!((stats.rms - (3.0f32 / 8.0).sqrt()).abs() < 1e-6, "rms {}", stats.rms);
+		assert_eq!(stats.dc, 0.25);
+		assert!(stats.clipped && !stats.nan);
+
+		let stats = monitor.push(&[0.25, f32::NAN, 0.25, 0.25, 0.25, 0.25, 0.25, 0.25]).unwrap();
+		assert_eq!((stats.peak, stats.rms, stats.dc), (0.25, 0.25, 0.25));
+		assert!(stats.nan && !stats.clipped);
+	}
+}
@@ -1,10 +1,22 @@
 use std::f32::consts::PI;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::{ Duration, Instant };
+use std::cmp::Reverse;
+use std::fmt;
+use std::mem;
 
-#[derive(PartialEq, Debug)]
+use crate::fft::{ Fft, Complex };
+use crate::midi::MidiState;
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct Phase {
 	phase: f32,
 	phase_step: f32,
-	period: f32
+	period: f32,
+	increment: Option<f32>,
+	declick: f32
 }
 
 impl Phase {
@@ -12,17 +24,703 @@ impl Phase {
 		Phase {
 			period,
 			phase: 0.0,
-			phase_step: (PI * 2.0) / sample_rate as f32
+			phase_step: (PI * 2.0) / sample_rate as f32,
+			increment: None,
+			declick: 1.0
 		}
 	}
 
+	/// Smooths frequency changes over roughly `samples` samples by easing the
+	/// phase increment instead of jumping to it. 0 disables smoothing.
+	pub fn set_declick(&mut self, samples: u32) {
+		self.declick = 1.0 / samples.max(1) as f32;
+	}
+
+	/// Picks up where `other` left off, keeping this phase's own step and
+	/// declick settings.
+	pub fn resume_from(&mut self, other: &Phase) {
+		self.phase = other.phase % self.period;
+		self.increment = other.increment;
+	}
+
+	/// Phase advanced by the last `advance` call, in radians.
+	pub fn increment(&self) -> f32 {
+		self.increment.unwrap_or(0.0)
+	}
+
+	/// Steps the phase by one sample at `freq` Hz and returns it in radians,
+	/// wrapped to `0..period` (always `0..2π` for oscillators).
 	pub fn advance(&mut self, freq: f32) -> f32 {
-		self.phase += self.phase_step * freq;
+		let target = self.phase_step * freq;
+		let inc = match self.increment {
+			Some(inc) => inc + (target - inc) * self.declick,
+			None => target
+		};
+		self.increment = Some(inc);
+		self.phase += inc;
 		self.phase %= self.period;
 		self.phase
 	}
 }
 
+const CONVOLVE_BLOCK: usize = 256;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Convolver {
+	fft: Fft,
+	partitions: Vec<Vec<Complex>>,
+	history: Vec<Vec<Complex>>,
+	acc: Vec<Complex>,
+	head: usize,
+	input: Vec<f32>,
+	output: Vec<f32>,
+	pos: usize
+}
+
+impl Convolver {
+	pub fn new(ir: &[f32]) -> Convolver {
+		let size = CONVOLVE_BLOCK * 2;
+		let fft = Fft::new(size);
+		let partitions: Vec<Vec<Complex>> = ir.chunks(CONVOLVE_BLOCK).map(|part| {
+			let mut spec = vec![Complex::zero(); size];
+			for (i, s) in part.iter().enumerate() {
+				spec[i].re = *s;
+			}
+			fft.process(&mut spec, false);
+			spec
+		}).collect();
+		let count = partitions.len().max(1);
+		Convolver {
+			fft,
+			partitions,
+			history: vec![vec![Complex::zero(); size]; count],
+			acc: vec![Complex::zero(); size],
+			head: 0,
+			input: vec![0.0; size],
+			output: vec![0.0; CONVOLVE_BLOCK],
+			pos: 0
+		}
+	}
+
+	// Uniformly partitioned overlap-save, one block of latency.
+	pub fn process(&mut self, x: f32) -> f32 {
+		let y = self.output[self.pos];
+		self.input[CONVOLVE_BLOCK + self.pos] = x;
+		self.pos += 1;
+		if self.pos == CONVOLVE_BLOCK {
+			self.pos = 0;
+			self.process_block();
+		}
+		y
+	}
+
+	fn process_block(&mut self) {
+		let count = self.history.len();
+
+		let spec = &mut self.history[self.head];
+		for (c, s) in spec.iter_mut().zip(self.input.iter()) {
+			*c = Complex::new(*s, 0.0);
+		}
+		self.fft.process(spec, false);
+
+		let acc = &mut self.acc;
+		for a in acc.iter_mut() {
+			*a = Complex::zero();
+		}
+		for (p, part) in self.partitions.iter().enumerate() {
+			let hist = &self.history[(self.head + count - p) % count];
+			for (a, (x, h)) in acc.iter_mut().zip(hist.iter().zip(part.iter())) {
+				*a = *a + *x * *h;
+			}
+		}
+		self.fft.process(acc, true);
+
+		for (o, c) in self.output.iter_mut().zip(acc[CONVOLVE_BLOCK..].iter()) {
+			*o = c.re;
+		}
+		self.input.copy_within(CONVOLVE_BLOCK.., 0);
+		self.head = (self.head + 1) % count;
+	}
+}
+
+const FREEZE_SIZE: usize = 2048;
+const FREEZE_HOP: usize = FREEZE_SIZE / 4;
+
+fn hann(i: usize, size: usize) -> f32 {
+	0.5 - 0.5 * (PI * 2.0 * i as f32 / size as f32).cos()
+}
+
+fn wrap_phase(ph: f32) -> f32 {
+	ph - (PI * 2.0) * ((ph + PI) / (PI * 2.0)).floor()
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Freezer {
+	fft: Fft,
+	spec: Vec<Complex>,
+	history: Vec<f32>,
+	write: usize,
+	mags: Vec<f32>,
+	steps: Vec<f32>,
+	phases: Vec<f32>,
+	output: Vec<f32>,
+	pos: usize,
+	held: bool
+}
+
+impl Freezer {
+	pub fn new() -> Freezer {
+		let bins = FREEZE_SIZE / 2 + 1;
+		Freezer {
+			fft: Fft::new(FREEZE_SIZE),
+			spec: vec![Complex::zero(); FREEZE_SIZE],
+			history: vec![0.0; FREEZE_SIZE + FREEZE_HOP],
+			write: 0,
+			mags: vec![0.0; bins],
+			steps: vec![0.0; bins],
+			phases: vec![0.0; bins],
+			output: vec![0.0; FREEZE_SIZE],
+			pos: 0,
+			held: false
+		}
+	}
+
+	pub fn process(&mut self, x: f32, hold: bool) -> f32 {
+		if !hold {
+			self.held = false;
+			self.history[self.write] = x;
+			self.write = (self.write + 1) % self.history.len();
+			return x;
+		}
+
+		if !self.held {
+			self.held = true;
+			self.capture();
+			self.pos = 0;
+			for o in self.output.iter_mut() {
+				*o = 0.0;
+			}
+			self.synthesize();
+		}
+
+		let y = self.output[self.pos];
+		self.pos += 1;
+		if self.pos == FREEZE_HOP {
+			self.pos = 0;
+			self.output.copy_within(FREEZE_HOP.., 0);
+			for o in self.output[FREEZE_SIZE - FREEZE_HOP..].iter_mut() {
+				*o = 0.0;
+			}
+			self.synthesize();
+		}
+		y
+	}
+
+	// Analyzes two frames one hop apart so each bin keeps its true frequency.
+	fn capture(&mut self) {
+		self.analyze(0);
+		for k in 0..self.phases.len() {
+			let a = self.spec[k];
+			self.phases[k] = a.im.atan2(a.re);
+		}
+		self.analyze(FREEZE_HOP);
+		for k in 0..self.mags.len() {
+			let b = self.spec[k];
+			let pb = b.im.atan2(b.re);
+			self.mags[k] = (b.re * b.re + b.im * b.im).sqrt();
+			self.steps[k] = wrap_phase(pb - self.phases[k]);
+			self.phases[k] = pb;
+		}
+	}
+
+	// Windowed spectrum of the frame `offset` samples into the history.
+	fn analyze(&mut self, offset: usize) {
+		let len = self.history.len();
+		for (i, c) in self.spec.iter_mut().enumerate() {
+			let s = self.history[(self.write + offset + i) % len];
+			*c = Complex::new(s * hann(i, FREEZE_SIZE), 0.0);
+		}
+		self.fft.process(&mut self.spec, false);
+	}
+
+	fn synthesize(&mut self) {
+		let spec = &mut self.spec;
+		for c in spec.iter_mut() {
+			*c = Complex::zero();
+		}
+		for k in 0..self.mags.len() {
+			self.phases[k] = wrap_phase(self.phases[k] + self.steps[k]);
+			let c = Complex::new(
+				self.mags[k] * self.phases[k].cos(),
+				self.mags[k] * self.phases[k].sin()
+			);
+			spec[k] = c;
+			if k > 0 && k < FREEZE_SIZE / 2 {
+				spec[FREEZE_SIZE - k] = Complex::new(c.re, -c.im);
+			}
+		}
+		self.fft.process(spec, true);
+
+		// Hann analysis * Hann synthesis at 75% overlap sums to 1.5.
+		for (i, c) in spec.iter().enumerate() {
+			self.output[i] += c.re * hann(i, FREEZE_SIZE) / 1.5;
+		}
+	}
+}
+
+const PITCH_WINDOW: usize = 2048;
+const PITCH_HOP: usize = PITCH_WINDOW / 4;
+const PITCH_MIN_FREQ: f32 = 40.0;
+const PITCH_MAX_FREQ: f32 = 2000.0;
+
+// Autocorrelation pitch estimate, normalized as in McLeod's NSDF so the
+// fundamental isn't mistaken for one of its octaves.
+#[derive(PartialEq, Debug, Clone)]
+pub struct PitchTracker {
+	fft: Fft,
+	frame: Vec<f32>,
+	spec: Vec<Complex>,
+	nsdf: Vec<f32>,
+	peaks: Vec<usize>,
+	history: Vec<f32>,
+	write: usize,
+	filled: usize,
+	since: usize,
+	freq: f32,
+	sample_rate: u32
+}
+
+impl PitchTracker {
+	pub fn new(sample_rate: u32) -> PitchTracker {
+		PitchTracker {
+			fft: Fft::new(PITCH_WINDOW * 2),
+			frame: vec![0.0; PITCH_WINDOW],
+			spec: vec![Complex::zero(); PITCH_WINDOW * 2],
+			nsdf: Vec::new(),
+			peaks: Vec::new(),
+			history: vec![0.0; PITCH_WINDOW],
+			write: 0,
+			filled: 0,
+			since: 0,
+			freq: 0.0,
+			sample_rate
+		}
+	}
+
+	/// Returns the latest estimate in Hz, holding it through unpitched input.
+	pub fn process(&mut self, x: f32) -> f32 {
+		self.history[self.write] = x;
+		self.write = (self.write + 1) % PITCH_WINDOW;
+		self.filled = (self.filled + 1).min(PITCH_WINDOW);
+		self.since += 1;
+		if self.since >= PITCH_HOP && self.filled == PITCH_WINDOW {
+			self.since = 0;
+			if let Some(freq) = self.estimate() {
+				self.freq = freq;
+			}
+		}
+		self.freq
+	}
+
+	fn estimate(&mut self) -> Option<f32> {
+		let mean = self.history.iter().sum::<f32>() / PITCH_WINDOW as f32;
+		let (frame, spec) = (&mut self.frame, &mut self.spec);
+		for (i, f) in frame.iter_mut().enumerate() {
+			*f = self.history[(self.write + i) % PITCH_WINDOW] - mean;
+		}
+
+		for (i, c) in spec.iter_mut().enumerate() {
+			*c = Complex::new(frame.get(i).cloned().unwrap_or(0.0), 0.0);
+		}
+		self.fft.process(spec, false);
+		for c in spec.iter_mut() {
+			*c = Complex::new(c.re * c.re + c.im * c.im, 0.0);
+		}
+		self.fft.process(spec, true);
+
+		let rate = self.sample_rate as f32;
+		let min_lag = ((rate / PITCH_MAX_FREQ) as usize).max(2);
+		let max_lag = ((rate / PITCH_MIN_FREQ) as usize).min(PITCH_WINDOW / 2);
+		let mut energy: f32 = frame.iter().map(|s| s * s).sum::<f32>() * 2.0;
+		if energy < 1e-6 {
+			return None;
+		}
+		let nsdf = &mut self.nsdf;
+		nsdf.clear();
+		for lag in 0..max_lag + 2 {
+			if lag > 0 {
+				energy -= frame[lag - 1] * frame[lag - 1] + frame[PITCH_WINDOW - lag] * frame[PITCH_WINDOW - lag];
+			}
+			nsdf.push(if energy > 1e-9 { 2.0 * spec[lag].re / energy } else { 0.0 });
+		}
+
+		// Highest point of each positive lobe after the first zero crossing.
+		let peaks = &mut self.peaks;
+		peaks.clear();
+		let mut lag = 1;
+		while lag <= max_lag && nsdf[lag] > 0.0 {
+			lag += 1;
+		}
+		while lag <= max_lag {
+			while lag <= max_lag && nsdf[lag] <= 0.0 {
+				lag += 1;
+			}
+			let mut best = lag;
+			while lag <= max_lag && nsdf[lag] > 0.0 {
+				if nsdf[lag] > nsdf[best] {
+					best = lag;
+				}
+				lag += 1;
+			}
+			if best <= max_lag && best >= min_lag {
+				peaks.push(best);
+			}
+		}
+
+		let highest = peaks.iter().map(|&p| nsdf[p]).fold(0.0f32, f32::max);
+		if highest < 0.5 {
+			return None;
+		}
+		let peak = *peaks.iter().find(|&&p| nsdf[p] >= highest * 0.9)?;
+
+		let (a, b, c) = (nsdf[peak - 1], nsdf[peak], nsdf[peak + 1]);
+		let denom = a - 2.0 * b + c;
+		let offset = if denom.abs() > 1e-9 { 0.5 * (a - c) / denom } else { 0.0 };
+		Some(rate / (peak as f32 + offset))
+	}
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum NoiseKind {
+	White,
+	Pink
+}
+
+fn mix64(x: u64) -> u64 {
+	let mut x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+	x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+	x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+	x ^ (x >> 31)
+}
+
+// Xorshift white noise, optionally through Paul Kellet's pink filter.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct NoiseGen {
+	seed: Option<u32>,
+	state: u64,
+	pink: [f32; 7]
+}
+
+impl NoiseGen {
+	/// A stream fixed by `seed` alone, independent of the graph.
+	pub fn seeded(seed: u32) -> NoiseGen {
+		NoiseGen::from_key(Some(seed), u64::from(seed))
+	}
+
+	/// A stream derived from the graph's master seed and the node's id.
+	pub fn derived(master: u32, id: usize) -> NoiseGen {
+		// Explicit seeds use keys below 2^32 and derived ones always have the
+		// top bit set, so the two never land on the same stream.
+		let key = mix64((u64::from(master) << 32) | (id as u64 & 0xFFFF_FFFF)) | (1 << 63);
+		NoiseGen::from_key(None, key)
+	}
+
+	fn from_key(seed: Option<u32>, key: u64) -> NoiseGen {
+		// mix64 is a bijection, so distinct keys give distinct starting states.
+		let x = mix64(key);
+		NoiseGen {
+			seed,
+			state: if x == 0 { 1 } else { x },
+			pink: [0.0; 7]
+		}
+	}
+
+	/// The explicit seed, if the stream isn't derived from the graph.
+	pub fn seed(&self) -> Option<u32> {
+		self.seed
+	}
+
+	pub fn white(&mut self) -> f32 {
+		let mut x = self.state;
+		x ^= x << 13;
+		x ^= x >> 7;
+		x ^= x << 17;
+		self.state = x;
+		((x >> 32) as f32 / u32::MAX as f32) * 2.0 - 1.0
+	}
+
+	pub fn pink(&mut self) -> f32 {
+		let w = self.white();
+		let b = &mut self.pink;
+		b[0] = 0.99886 * b[0] + w * 0.055_517_9;
+		b[1] = 0.99332 * b[1] + w * 0.075_075_9;
+		b[2] = 0.96900 * b[2] + w * 0.153_852;
+		b[3] = 0.86650 * b[3] + w * 0.310_485_6;
+		b[4] = 0.55000 * b[4] + w * 0.532_952_2;
+		b[5] = -0.7616 * b[5] - w * 0.016_898;
+		let out = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + w * 0.5362;
+		b[6] = w * 0.115_926;
+		out * 0.11
+	}
+}
+
+const DELAY_MAX_SECONDS: f32 = 4.0;
+
+/// How delay lines read between samples. Cubic costs a little more but
+/// adds less noise when the delay time is modulated.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DelayInterp {
+	Linear,
+	Cubic
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct DelayLine {
+	buffer: Vec<f32>,
+	write: usize
+}
+
+impl DelayLine {
+	pub fn new(sample_rate: u32) -> DelayLine {
+		DelayLine {
+			buffer: vec![0.0; (DELAY_MAX_SECONDS * sample_rate as f32) as usize + 3],
+			write: 0
+		}
+	}
+
+	pub fn process(&mut self, x: f32, delay_samples: f32, feedback: f32, mix: f32, interp: DelayInterp) -> f32 {
+		let len = self.buffer.len();
+		let delay = delay_samples.max(1.0).min((len - 3) as f32);
+		let whole = delay.floor() as usize;
+		let frac = delay - whole as f32;
+		let tap = |d: usize| self.buffer[(self.write + len - d) % len];
+		let (a, b) = (tap(whole), tap(whole + 1));
+		let delayed = match interp {
+			DelayInterp::Linear => a + (b - a) * frac,
+			// 4-point Hermite; a delay of 0 is the current input.
+			DelayInterp::Cubic => {
+				let prev = if whole > 1 { tap(whole - 1) } else { x };
+				let next = tap(whole + 2);
+				let c1 = (b - prev) * 0.5;
+				let c2 = prev - a * 2.5 + b * 2.0 - next * 0.5;
+				let c3 = (next - prev) * 0.5 + (a - b) * 1.5;
+				((c3 * frac + c2) * frac + c1) * frac + a
+			}
+		};
+
+		self.buffer[self.write] = x + delayed * feedback.clamp(-0.99, 0.99);
+		self.write = (self.write + 1) % len;
+		let mix = mix.clamp(0.0, 1.0);
+		x * (1.0 - mix) + delayed * mix
+	}
+}
+
+const AUTO_GAIN_MAX: f32 = 16.0;
+// Below this RMS (-60 dBFS) the source is treated as silent.
+const AUTO_GAIN_FLOOR: f32 = 1e-3;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Leveler {
+	time_ms: f32,
+	coeff: f32,
+	power: f32,
+	gain: f32
+}
+
+impl Leveler {
+	pub fn new(time_ms: f32, sample_rate: u32) -> Leveler {
+		let samples = (time_ms.max(1.0) / 1000.0) * sample_rate as f32;
+		Leveler {
+			time_ms,
+			coeff: 1.0 - (-1.0 / samples).exp(),
+			power: 0.0,
+			gain: 1.0
+		}
+	}
+
+	pub fn time_ms(&self) -> f32 {
+		self.time_ms
+	}
+
+	pub fn process(&mut self, x: f32, target_rms: f32) -> f32 {
+		self.power += (x * x - self.power) * self.coeff;
+		let rms = self.power.sqrt();
+		// Relax toward unity during silence instead of boosting, so the next
+		// note doesn't start at full gain.
+		let desired = if rms > AUTO_GAIN_FLOOR {
+			(target_rms / rms).min(AUTO_GAIN_MAX)
+		} else {
+			1.0
+		};
+		self.gain += (desired - self.gain) * self.coeff;
+		x * self.gain
+	}
+}
+
+const TILT_PIVOT: f32 = 700.0;
+const TILT_MAX_DB: f32 = 6.0;
+
+// Splits the signal around the pivot with a one-pole low-pass, then cuts one
+// side and boosts the other, i.e. a low shelf and a high shelf in opposition.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Tilter {
+	coeff: f32,
+	low: f32
+}
+
+impl Tilter {
+	pub fn new(sample_rate: u32) -> Tilter {
+		Tilter {
+			coeff: 1.0 - (-PI * 2.0 * TILT_PIVOT / sample_rate as f32).exp(),
+			low: 0.0
+		}
+	}
+
+	pub fn process(&mut self, x: f32, amount: f32) -> f32 {
+		self.low += (x - self.low) * self.coeff;
+		let high = x - self.low;
+		let gain = 10.0f32.powf(amount.clamp(-1.0, 1.0) * TILT_MAX_DB / 20.0);
+		self.low / gain + high * gain
+	}
+}
+
+// Position within the current `division`-beat cycle, 0..1.
+fn beat_phase(beats: f64, division: f32) -> f32 {
+	if division <= 0.0 {
+		return 0.0;
+	}
+	(beats / f64::from(division)).fract() as f32
+}
+
+const OSC_SHAPES: usize = 4;
+
+// Two-sample polynomial step residual for a discontinuity at `t` = 0, with
+// `t` the normalized phase and `dt` the normalized increment.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+	if dt <= 0.0 {
+		0.0
+	} else if t < dt {
+		let x = t / dt;
+		x + x - x * x - 1.0
+	} else if t > 1.0 - dt {
+		let x = (t - 1.0) / dt;
+		x * x + x + x + 1.0
+	} else {
+		0.0
+	}
+}
+
+// Sine, triangle, saw and square over a 0..2π phase.
+fn osc_shape(shape: usize, ph: f32) -> f32 {
+	let t = ph / (PI * 2.0);
+	match shape {
+		0 => ph.sin(),
+		1 => if ph < PI { -1.0 + (2.0 / PI) * ph } else { 3.0 - (2.0 / PI) * ph },
+		2 => t * 2.0 - 1.0,
+		_ => if t < 0.5 { 1.0 } else { -1.0 }
+	}
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Channel {
+	Left,
+	Right
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum FilterMode {
+	LowPass,
+	HighPass
+}
+
+// Trapezoidal state-variable filter, stable under fast cutoff modulation.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Svf {
+	ic1eq: f32,
+	ic2eq: f32
+}
+
+impl Svf {
+	pub fn new() -> Svf {
+		Svf { ic1eq: 0.0, ic2eq: 0.0 }
+	}
+
+	/// Returns the (low, band, high) outputs for one sample.
+	pub fn process(&mut self, x: f32, cutoff: f32, q: f32, sample_rate: u32) -> (f32, f32, f32) {
+		let sr = sample_rate as f32;
+		let fc = cutoff.max(1.0).min(sr * 0.49);
+		let g = (PI * fc / sr).tan();
+		let k = 1.0 / q.max(0.05);
+		let a1 = 1.0 / (1.0 + g * (g + k));
+		let a2 = g * a1;
+		let a3 = g * a2;
+
+		let v3 = x - self.ic2eq;
+		let v1 = a1 * self.ic1eq + a2 * v3;
+		let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+		self.ic1eq = 2.0 * v1 - self.ic1eq;
+		self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+		(v2, v1, x - k * v1 - v2)
+	}
+}
+
+const WAH_Q: f32 = 4.0;
+const WAH_OCTAVES: f32 = 4.0;
+
+// Envelope follower driving a band-pass cutoff up from its base.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Wah {
+	svf: Svf,
+	env: f32,
+	attack: f32,
+	release: f32
+}
+
+impl Wah {
+	pub fn new(sample_rate: u32) -> Wah {
+		let coeff = |ms: f32| 1.0 - (-1000.0 / (ms * sample_rate as f32)).exp();
+		Wah {
+			svf: Svf::new(),
+			env: 0.0,
+			attack: coeff(5.0),
+			release: coeff(150.0)
+		}
+	}
+
+	pub fn process(&mut self, x: f32, sensitivity: f32, base_cutoff: f32, sample_rate: u32) -> f32 {
+		let level = x.abs();
+		let coeff = if level > self.env { self.attack } else { self.release };
+		self.env += (level - self.env) * coeff;
+
+		let sweep = (self.env * sensitivity).clamp(0.0, 1.0);
+		let cutoff = base_cutoff * 2.0f32.powf(sweep * WAH_OCTAVES);
+		let (_, band, _) = self.svf.process(x, cutoff, WAH_Q, sample_rate);
+		band / WAH_Q
+	}
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Holder {
+	value: f32,
+	trigger: f32
+}
+
+impl Holder {
+	pub fn new() -> Holder {
+		Holder { value: 0.0, trigger: 0.0 }
+	}
+
+	/// Captures `x` when `trigger` rises above 0 and returns the held value.
+	pub fn process(&mut self, x: f32, trigger: f32) -> f32 {
+		if trigger > 0.0 && self.trigger <= 0.0 {
+			self.value = x;
+		}
+		self.trigger = trigger;
+		self.value
+	}
+}
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Input {
 	Value(f32),
@@ -34,14 +732,13 @@ impl Input {
 	pub fn sample(self, ctx: InputContext) -> f32 {
 		match self {
 			Input::Value(v) => v,
-			Input::Node(id) => ctx.outputs[id],
-			Input::Store(id) => ctx.store[id],
-			_ => 0.0
+			Input::Node(id) => ctx.outputs.get(id).cloned().unwrap_or(0.0),
+			Input::Store(id) => ctx.store.get(id).cloned().unwrap_or(0.0)
 		}
 	}
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum Node {
 	Null,
 
@@ -49,19 +746,220 @@ pub enum Node {
 	Sine(Phase, Input, Input),
 	Square(Phase, Input, Input),
 	Triangle(Phase, Input, Input),
+	SawBL(Phase, Input, Input),
+	SquareBL(Phase, Input, Input),
+
+	Osc(Phase, Input, Input, Input),
+	Additive(Phase, Input, Vec<f32>),
+	Wavetable(Phase, Input, Input, usize),
+
+	Noise(NoiseGen, NoiseKind, Input),
 
-	LFO(Phase, Input),
-	Map(Input, f32, f32, f32, f32),
+	LFO(Phase, Input, Input, Input, bool),
+	PulseLFO(Phase, Input, Input),
+	SyncLFO(Input),
+	Clock(Input),
+	SampleHold(Input, Input, Holder),
+	MidiFreq(usize),
+	MidiGate(usize),
+	Map(Input, f32, f32, f32, f32, bool),
+	Unipolar(Input),
+	Bipolar(Input),
 
 	Mix(Input, Input, f32),
+	Clip(Input, Input),
+	AM(Input, Input, Input, u8),
+
+	Filter(Svf, FilterMode, Input, Input, Input),
+	AutoWah(Input, Input, Input, Wah),
+
+	Convolve(Input, Convolver),
+
+	Delay(Input, Input, Input, Input, DelayLine),
+
+	PeriodSamples(Input),
+
+	AutoGain(Input, f32, Leveler),
+
+	Freeze(Input, Input, Freezer),
+
+	Tilt(Input, Input, Tilter),
+
+	PitchFollow(Input, PitchTracker),
 
 	Add(Input, Input),
 	Sub(Input, Input),
 	Mul(Input, Input),
+	Div(Input, Input),
+
+	Pan(Input, Input, Channel),
 
 	Writer(usize, Input),
 
-	Output(Input)
+	Output(Input),
+	StereoOutput(Input, Input)
+}
+
+/// Nodes that can't be ordered because they depend on each other, along
+/// with anything downstream of them.
+#[derive(PartialEq, Debug)]
+pub struct CycleError {
+	pub nodes: Vec<usize>
+}
+
+impl fmt::Display for CycleError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Cycle between nodes {:?}", self.nodes)
+	}
+}
+
+/// A graph has a single output; declaring a second one is an error rather
+/// than silently replacing the first.
+#[derive(PartialEq, Debug)]
+pub struct DuplicateOutputError {
+	pub existing: usize
+}
+
+impl fmt::Display for DuplicateOutputError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Output already declared (node {})", self.existing)
+	}
+}
+
+/// A builder was handed an id that doesn't refer to anything in the graph.
+#[derive(PartialEq, Debug)]
+pub enum GraphError {
+	InvalidStore(usize),
+	InvalidNode(usize),
+	MissingWavetable(usize)
+}
+
+impl fmt::Display for GraphError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			GraphError::InvalidStore(id) => write!(f, "Store {} doesn't exist", id),
+			GraphError::InvalidNode(id) => write!(f, "Node {} doesn't exist", id),
+			GraphError::MissingWavetable(id) => write!(f, "Wavetable {} doesn't exist or is empty", id)
+		}
+	}
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Param {
+	pub node: usize,
+	pub index: usize,
+	pub value: f32
+}
+
+impl Node {
+	pub fn inputs(&self) -> Vec<Input> {
+		match self {
+			Node::Null | Node::MidiFreq(_) | Node::MidiGate(_) => Vec::new(),
+			Node::Saw(_, a, b) | Node::Sine(_, a, b) | Node::Square(_, a, b) | Node::Triangle(_, a, b) |
+			Node::SawBL(_, a, b) | Node::SquareBL(_, a, b) |
+			Node::PulseLFO(_, a, b) | Node::Wavetable(_, a, b, _) => vec![*a, *b],
+			Node::Osc(_, a, b, c) | Node::Filter(_, _, a, b, c) | Node::AM(a, b, c, _) |
+			Node::AutoWah(a, b, c, _) | Node::LFO(_, a, b, c, _) => vec![*a, *b, *c],
+			Node::Delay(a, b, c, d, _) => vec![*a, *b, *c, *d],
+			Node::Additive(_, a, _) | Node::SyncLFO(a) | Node::Clock(a) |
+			Node::Noise(_, _, a) => vec![*a],
+			Node::Map(a, ..) | Node::Unipolar(a) | Node::Bipolar(a) |
+			Node::Convolve(a, _) | Node::PeriodSamples(a) | Node::AutoGain(a, ..) | Node::PitchFollow(a, _) |
+			Node::Writer(_, a) | Node::Output(a) => vec![*a],
+			Node::Mix(a, b, _) | Node::Freeze(a, b, _) | Node::SampleHold(a, b, _) | Node::Tilt(a, b, _) | Node::Pan(a, b, _) |
+			Node::StereoOutput(a, b) | Node::Clip(a, b) |
+			Node::Add(a, b) | Node::Sub(a, b) | Node::Mul(a, b) | Node::Div(a, b) => vec![*a, *b]
+		}
+	}
+
+	pub fn inputs_mut(&mut self) -> Vec<&mut Input> {
+		match self {
+			Node::Null | Node::MidiFreq(_) | Node::MidiGate(_) => Vec::new(),
+			Node::Saw(_, a, b) | Node::Sine(_, a, b) | Node::Square(_, a, b) | Node::Triangle(_, a, b) |
+			Node::SawBL(_, a, b) | Node::SquareBL(_, a, b) |
+			Node::PulseLFO(_, a, b) | Node::Wavetable(_, a, b, _) => vec![a, b],
+			Node::Osc(_, a, b, c) | Node::Filter(_, _, a, b, c) | Node::AM(a, b, c, _) |
+			Node::AutoWah(a, b, c, _) | Node::LFO(_, a, b, c, _) => vec![a, b, c],
+			Node::Delay(a, b, c, d, _) => vec![a, b, c, d],
+			Node::Additive(_, a, _) | Node::SyncLFO(a) | Node::Clock(a) |
+			Node::Noise(_, _, a) => vec![a],
+			Node::Map(a, ..) | Node::Unipolar(a) | Node::Bipolar(a) |
+			Node::Convolve(a, _) | Node::PeriodSamples(a) | Node::AutoGain(a, ..) | Node::PitchFollow(a, _) |
+			Node::Writer(_, a) | Node::Output(a) => vec![a],
+			Node::Mix(a, b, _) | Node::Freeze(a, b, _) | Node::SampleHold(a, b, _) | Node::Tilt(a, b, _) | Node::Pan(a, b, _) |
+			Node::StereoOutput(a, b) | Node::Clip(a, b) |
+			Node::Add(a, b) | Node::Sub(a, b) | Node::Mul(a, b) | Node::Div(a, b) => vec![a, b]
+		}
+	}
+
+	pub fn phase(&self) -> Option<&Phase> {
+		match self {
+			Node::Saw(p, ..) | Node::Sine(p, ..) | Node::Square(p, ..) | Node::Triangle(p, ..) |
+			Node::SawBL(p, ..) | Node::SquareBL(p, ..) |
+			Node::Osc(p, ..) | Node::Additive(p, ..) | Node::Wavetable(p, ..) | Node::LFO(p, ..) |
+			Node::PulseLFO(p, ..) => Some(p),
+			_ => None
+		}
+	}
+
+	pub fn phase_mut(&mut self) -> Option<&mut Phase> {
+		match self {
+			Node::Saw(p, ..) | Node::Sine(p, ..) | Node::Square(p, ..) | Node::Triangle(p, ..) |
+			Node::SawBL(p, ..) | Node::SquareBL(p, ..) |
+			Node::Osc(p, ..) | Node::Additive(p, ..) | Node::Wavetable(p, ..) | Node::LFO(p, ..) |
+			Node::PulseLFO(p, ..) => Some(p),
+			_ => None
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Node::Null => "Null",
+			Node::Saw(..) => "Saw",
+			Node::Sine(..) => "Sine",
+			Node::Noise(_, NoiseKind::White, _) => "Noise",
+			Node::Noise(_, NoiseKind::Pink, _) => "PinkNoise",
+			Node::Square(..) => "Square",
+			Node::Triangle(..) => "Triangle",
+			Node::SawBL(..) => "SawBL",
+			Node::SquareBL(..) => "SquareBL",
+			Node::Osc(..) => "Osc",
+			Node::Additive(..) => "Additive",
+			Node::Wavetable(..) => "Wavetable",
+			Node::LFO(.., false) => "LFO",
+			Node::LFO(.., true) => "LFOBi",
+			Node::PulseLFO(..) => "PulseLFO",
+			Node::SyncLFO(..) => "SyncLFO",
+			Node::Clock(..) => "Clock",
+			Node::SampleHold(..) => "SampleHold",
+			Node::MidiFreq(_) => "MidiFreq",
+			Node::MidiGate(_) => "MidiGate",
+			Node::Map(.., false) => "Map",
+			Node::Map(.., true) => "MapClamped",
+			Node::Unipolar(..) => "Unipolar",
+			Node::Bipolar(..) => "Bipolar",
+			Node::Mix(..) => "Mix",
+			Node::Clip(..) => "Clip",
+			Node::AM(..) => "AM",
+			Node::Filter(..) => "Filter",
+			Node::Convolve(..) => "Convolve",
+			Node::PeriodSamples(..) => "PeriodSamples",
+			Node::AutoGain(..) => "AutoGain",
+			Node::Tilt(..) => "Tilt",
+			Node::Delay(..) => "Delay",
+			Node::AutoWah(..) => "AutoWah",
+			Node::PitchFollow(..) => "PitchFollow",
+			Node::Freeze(..) => "Freeze",
+			Node::Add(..) => "Add",
+			Node::Sub(..) => "Sub",
+			Node::Mul(..) => "Mul",
+			Node::Div(..) => "Div",
+			Node::Writer(..) => "Writer",
+			Node::Pan(..) => "Pan",
+			Node::Output(..) => "Output",
+			Node::StereoOutput(..) => "StereoOutput"
+		}
+	}
 }
 
 #[derive(Clone, Copy)]
@@ -73,11 +971,36 @@ struct InputContext<'outs, 'stor> {
 pub struct NodeGraph {
 	nodes: Vec<Node>,
 	dead: Vec<usize>,
+	order: Vec<usize>,
+	constraints: Vec<(usize, usize)>,
 	output_node: Option<usize>,
 
 	sample_rate: u32,
+	buffer_size: usize,
+	tempo: f32,
+	beats: f64,
+	click: bool,
+	midi: MidiState,
+	master_clip: bool,
+	declick: u32,
+	delay_interp: DelayInterp,
+	seed: u32,
 	outputs: Vec<f32>,
-	store: Vec<f32>
+	store: Vec<f32>,
+	store_smoothing: Vec<f32>,
+	store_names: HashMap<String, usize>,
+	wavetables: Vec<Vec<f32>>,
+	paths: HashMap<usize, String>,
+	scheduled: VecDeque<(u64, usize, f32)>,
+	samples_generated: u64,
+
+	profiling: bool,
+	profile: HashMap<&'static str, Duration>,
+
+	max_nodes: Option<usize>,
+	sample_budget: Option<Duration>,
+	budget_overruns: usize,
+	nan_frames: usize
 }
 
 impl NodeGraph {
@@ -85,59 +1008,644 @@ impl NodeGraph {
 		NodeGraph {
 			nodes: Vec::new(),
 			dead: Vec::new(),
+			order: Vec::new(),
+			constraints: Vec::new(),
 			outputs: Vec::new(),
 			store: Vec::new(),
+			store_smoothing: Vec::new(),
+			store_names: HashMap::new(),
+			wavetables: Vec::new(),
+			paths: HashMap::new(),
+			scheduled: VecDeque::new(),
+			samples_generated: 0,
+			profiling: false,
+			profile: HashMap::new(),
+			max_nodes: None,
+			sample_budget: None,
+			budget_overruns: 0,
+			nan_frames: 0,
 			output_node: None,
-			sample_rate
+			sample_rate,
+			buffer_size: 1024,
+			tempo: 120.0,
+			beats: 0.0,
+			click: false,
+			midi: MidiState::new(),
+			master_clip: true,
+			declick: 0,
+			delay_interp: DelayInterp::Linear,
+			seed: 0
+		}
+	}
+
+	/// Note state fed by the live MIDI input.
+	pub fn midi_mut(&mut self) -> &mut MidiState {
+		&mut self.midi
+	}
+
+	/// Carries oscillator phases, store values, the beat clock and the sample
+	/// clock over from `old`, so a reloaded patch continues instead of
+	/// restarting. Nodes are matched by id and only kept if their type is
+	/// unchanged; named stores are matched by name and the rest by id. Store
+	/// events scheduled before the current sample are dropped, since `old`
+	/// already played them.
+	pub fn migrate_from(&mut self, old: &NodeGraph) {
+		for (id, node) in self.nodes.iter_mut().enumerate() {
+			let prev = match old.nodes.get(id) {
+				Some(prev) if prev.name() == node.name() => prev,
+				_ => continue
+			};
+			if let (Some(p), Some(q)) = (node.phase_mut(), prev.phase()) {
+				p.resume_from(q);
+			}
+		}
+
+		let named: HashMap<usize, &String> = self.store_names.iter()
+			.map(|(name, id)| (*id, name))
+			.collect();
+		for id in 0..self.store.len() {
+			let from = match named.get(&id) {
+				Some(name) => old.store_names.get(*name).cloned(),
+				None => Some(id)
+			};
+			if let Some(value) = from.and_then(|from| old.store.get(from)) {
+				self.store[id] = *value;
+			}
+		}
+
+		self.beats = old.beats;
+		self.samples_generated = old.samples_generated;
+		let now = self.samples_generated;
+		self.scheduled.retain(|e| e.0 >= now);
+		if old.midi.voices() == self.midi.voices() {
+			self.midi = old.midi.clone();
+		}
+	}
+
+	pub fn sample_rate(&self) -> u32 {
+		self.sample_rate
+	}
+
+	/// Audio buffer size, in frames, the patch asks the device for.
+	pub fn set_buffer_size(&mut self, frames: usize) {
+		self.buffer_size = frames.max(1);
+	}
+
+	pub fn buffer_size(&self) -> usize {
+		self.buffer_size
+	}
+
+	/// Clamps both output channels to -1..1 and silences NaNs. On by default.
+	pub fn set_master_clip(&mut self, enabled: bool) {
+		self.master_clip = enabled;
+	}
+
+	pub fn master_clip(&self) -> bool {
+		self.master_clip
+	}
+
+	/// Mixes a metronome click on every beat into the output, accented on
+	/// the first beat of each bar of four.
+	pub fn set_click(&mut self, click: bool) {
+		self.click = click;
+	}
+
+	fn click_sample(&self) -> f32 {
+		const LENGTH: f32 = 0.03;
+		if !self.click || self.tempo <= 0.0 {
+			return 0.0;
+		}
+		let t = self.beats.fract() as f32 * 60.0 / self.tempo;
+		if t >= LENGTH {
+			return 0.0;
+		}
+		let (freq, amp) = if self.beats as u64 % 4 == 0 { (1500.0, 0.5) } else { (1000.0, 0.3) };
+		(PI * 2.0 * freq * t).sin() * amp * (1.0 - t / LENGTH)
+	}
+
+	/// Eases oscillator frequency changes over `samples` samples to avoid clicks.
+	pub fn set_declick(&mut self, samples: u32) {
+		self.declick = samples;
+		for n in self.nodes.iter_mut() {
+			if let Some(p) = n.phase_mut() {
+				p.set_declick(samples);
+			}
+		}
+	}
+
+	/// Interpolation used by every delay line. Linear by default.
+	pub fn set_delay_interp(&mut self, interp: DelayInterp) {
+		self.delay_interp = interp;
+	}
+
+	/// Sets the master seed that noise nodes without their own seed derive
+	/// their streams from.
+	pub fn set_seed(&mut self, seed: u32) {
+		self.seed = seed;
+		for (id, n) in self.nodes.iter_mut().enumerate() {
+			if let Node::Noise(gen, ..) = n {
+				if gen.seed().is_none() {
+					*gen = NoiseGen::derived(seed, id);
+				}
+			}
+		}
+	}
+
+	fn new_phase(&self) -> Phase {
+		let mut p = Phase::new(PI * 2.0, self.sample_rate);
+		p.set_declick(self.declick);
+		p
+	}
+
+	pub fn set_tempo(&mut self, bpm: f32) {
+		self.tempo = bpm.max(0.0);
+	}
+
+	pub fn tempo(&self) -> f32 {
+		self.tempo
+	}
+
+	/// Beats elapsed since the graph started, at the current tempo.
+	pub fn beat_position(&self) -> f64 {
+		self.beats
+	}
+
+	pub fn node_count(&self) -> usize {
+		self.nodes.len() - self.dead.len()
+	}
+
+	pub fn node(&self, id: usize) -> Option<&Node> {
+		match self.nodes.get(id) {
+			Some(Node::Null) | None => None,
+			n => n
+		}
+	}
+
+	pub fn output_node(&self) -> Option<usize> {
+		self.output_node
+	}
+
+	/// Live nodes with their ids, in creation-slot order.
+	pub fn live_nodes(&self) -> impl Iterator<Item = (usize, &Node)> {
+		self.nodes.iter().enumerate().filter(|(_, n)| **n != Node::Null)
+	}
+
+	/// Writes the graph back out as patch source that loads into an
+	/// equivalent graph. Nodes are named `n<id>` and listed in evaluation
+	/// order, ending with the output. `Wave` and `Convolve` nodes are written
+	/// with the file they were loaded from, or an empty path if they weren't.
+	pub fn to_source(&self) -> String {
+		let mut lines = Vec::new();
+		if self.sample_rate != 44100 {
+			lines.push(format!("@samplerate({})", self.sample_rate));
+		}
+		if self.buffer_size != 1024 {
+			lines.push(format!("@buffer({})", self.buffer_size));
+		}
+		if self.declick > 0 {
+			lines.push(format!("@declick({})", self.declick));
+		}
+		if self.delay_interp == DelayInterp::Cubic {
+			lines.push("@interp(\"cubic\")".to_owned());
+		}
+		if self.seed != 0 {
+			lines.push(format!("@seed({})", self.seed));
+		}
+		if self.tempo != 120.0 {
+			lines.push(format!("Tempo({})", self.tempo));
+		}
+
+		// Named stores keep their name when it's a valid identifier.
+		let mut stores: Vec<String> = (0..self.store.len()).map(|id| format!("s{}", id)).collect();
+		let mut names: Vec<(&String, &usize)> = self.store_names.iter().collect();
+		names.sort();
+		for (name, id) in names.into_iter().rev() {
+			let mut chars = name.chars();
+			let valid = chars.next().map_or(false, |c| c.is_ascii_alphabetic())
+				&& chars.all(|c| c.is_ascii_alphanumeric());
+			if valid {
+				stores[*id] = name.clone();
+			}
+		}
+		for (id, name) in stores.iter().enumerate() {
+			let k = self.store_smoothing[id];
+			let smooth_ms = if k < 1.0 {
+				-1000.0 / (self.sample_rate as f32 * (1.0 - k).ln())
+			} else {
+				0.0
+			};
+			lines.push(match smooth_ms > 0.0 {
+				true => format!("{} = CreateStore({}, {})", name, self.store[id], smooth_ms),
+				false => format!("{} = CreateStore({})", name, self.store[id])
+			});
+		}
+
+		// A Pan call makes a left and a right node, and so does a Delay or
+		// Convolve fed a stereo pair; only the left is written.
+		let is_pair = |pairs: &HashMap<usize, usize>, left: &Node, right: &Node| match (left, right) {
+			(Node::Pan(a, b, Channel::Left), Node::Pan(c, d, Channel::Right)) => a == c && b == d,
+			(Node::Delay(Input::Node(a), t, f, m, _), Node::Delay(Input::Node(b), u, g, n, _)) => {
+				pairs.get(a) == Some(b) && (t, f, m) == (u, g, n)
+			},
+			(Node::Convolve(Input::Node(a), _), Node::Convolve(Input::Node(b), _)) => pairs.get(a) == Some(b),
+			_ => false
+		};
+		let mut pairs: HashMap<usize, usize> = HashMap::new();
+		for (l, node) in self.live_nodes() {
+			let right = self.live_nodes()
+				.filter(|(r, n)| is_pair(&pairs, node, n) && self.paths.get(&l) == self.paths.get(r))
+				.filter(|(r, _)| !pairs.values().any(|p| p == r))
+				.map(|(r, _)| r)
+				.min_by_key(|r| (*r as isize - l as isize - 1).abs());
+			if let Some(r) = right {
+				pairs.insert(l, r);
+			}
+		}
+
+		let input = |i: Input| match i {
+			Input::Value(v) => v.to_string(),
+			Input::Node(id) => format!("n{}", id),
+			Input::Store(id) => stores[id].clone()
+		};
+
+		let order = self.topo_order().unwrap_or_else(|_| (0..self.nodes.len()).collect());
+		let mut output = None;
+		for id in order {
+			let node = &self.nodes[id];
+			let mut args: Vec<String> = node.inputs().into_iter().map(&input).collect();
+			let path = self.paths.get(&id).map_or("", |p| p.as_str());
+			let func = match node {
+				Node::Null | Node::Pan(_, _, Channel::Right) => continue,
+				_ if pairs.values().any(|r| *r == id) => continue,
+				Node::Filter(_, FilterMode::LowPass, ..) => "LowPass",
+				Node::Filter(_, FilterMode::HighPass, ..) => "HighPass",
+				Node::Wavetable(..) => "Wave",
+				n => n.name()
+			};
+			match node {
+				Node::Additive(_, _, amps) => args.extend(amps.iter().map(|a| a.to_string())),
+				Node::Wavetable(..) => args.insert(0, format!("\"{}\"", path)),
+				Node::Convolve(..) => args.push(format!("\"{}\"", path)),
+				Node::Noise(gen, ..) => args.extend(gen.seed().map(|s| s.to_string())),
+				Node::MidiFreq(v) | Node::MidiGate(v) if *v > 0 => args.push(v.to_string()),
+				Node::Map(_, a, b, c, d, _) => args.extend([a, b, c, d].iter().map(|v| v.to_string())),
+				Node::Mix(_, _, f) => args.push(f.to_string()),
+				Node::AM(.., mode) => args.push(mode.to_string()),
+				Node::AutoGain(_, target, lev) => {
+					args.push(target.to_string());
+					args.push(lev.time_ms().to_string());
+				},
+				Node::Writer(store, _) => args.insert(0, stores[*store].clone()),
+				Node::StereoOutput(Input::Node(l), Input::Node(r)) if pairs.get(l) == Some(r) => {
+					args = vec![format!("n{}", l)];
+				},
+				Node::StereoOutput(l, r) if l == r => args.truncate(1),
+				_ => {}
+			}
+
+			let call = format!("{}({})", func, args.join(", "));
+			if Some(id) == self.output_node {
+				output = Some(call);
+				continue;
+			}
+			let after: Vec<String> = self.constraints.iter()
+				.filter(|c| c.1 == id)
+				.map(|c| format!("n{}", c.0))
+				.collect();
+			lines.push(match after.is_empty() {
+				true => format!("n{} = {}", id, call),
+				false => format!("n{} = {} @after({})", id, call, after.join(", "))
+			});
+		}
+		lines.extend(output);
+
+		let mut src = lines.join("\n");
+		src.push('\n');
+		src
+	}
+
+	pub fn set_max_nodes(&mut self, limit: usize) {
+		self.max_nodes = Some(limit);
+	}
+
+	pub fn check_node_limit(&self) -> Result<(), String> {
+		match self.max_nodes {
+			Some(limit) if self.node_count() > limit => {
+				Err(format!("Graph has more than {} nodes.", limit))
+			},
+			_ => Ok(())
 		}
 	}
 
+	/// Warns when a single `sample()` call takes longer than `budget`.
+	pub fn set_sample_budget(&mut self, budget: Duration) {
+		self.sample_budget = Some(budget);
+	}
+
+	pub fn budget_overruns(&self) -> usize {
+		self.budget_overruns
+	}
+
+	/// Frames with a NaN in either channel since the last call. They're
+	/// counted before the master clip silences them.
+	pub fn take_nan_frames(&mut self) -> usize {
+		mem::replace(&mut self.nan_frames, 0)
+	}
+
+	pub fn name_store(&mut self, name: &str, id: usize) {
+		self.store_names.insert(name.to_owned(), id);
+	}
+
+	pub fn store_by_name(&self, name: &str) -> Option<usize> {
+		self.store_names.get(name).cloned()
+	}
+
+	pub fn set_store(&mut self, id: usize, value: f32) -> bool {
+		match self.store.get_mut(id) {
+			Some(s) => {
+				*s = value;
+				true
+			},
+			None => false
+		}
+	}
+
+	pub fn samples_generated(&self) -> u64 {
+		self.samples_generated
+	}
+
+	/// Sets `store` to `value` right before sample number `at` is generated.
+	pub fn schedule_store(&mut self, at: u64, store: usize, value: f32) {
+		let pos = self.scheduled.iter().rposition(|e| e.0 <= at).map_or(0, |i| i + 1);
+		self.scheduled.insert(pos, (at, store, value));
+	}
+
 	pub fn create_value_store(&mut self) -> usize {
+		self.create_smoothed_store(0.0, 0.0)
+	}
+
+	/// Creates a store whose writes are one-pole smoothed over `smooth_ms`.
+	pub fn create_smoothed_store(&mut self, initial: f32, smooth_ms: f32) -> usize {
 		self.store.push(0.0);
-		self.store.len() - 1
+		self.store_smoothing.push(1.0);
+		let id = self.store.len() - 1;
+		self.configure_store(id, initial, smooth_ms);
+		id
 	}
 
-	pub fn create_output(&mut self, from: Input) -> usize {
+	/// The store called `name`, created on first use. Lets a patch read a
+	/// store before the line that sets it up.
+	pub fn create_named_store(&mut self, name: &str) -> usize {
+		match self.store_by_name(name) {
+			Some(id) => id,
+			None => {
+				let id = self.create_value_store();
+				self.name_store(name, id);
+				id
+			}
+		}
+	}
+
+	pub fn configure_store(&mut self, id: usize, initial: f32, smooth_ms: f32) {
+		let coeff = if smooth_ms > 0.0 {
+			1.0 - (-1000.0 / (smooth_ms * self.sample_rate as f32)).exp()
+		} else {
+			1.0
+		};
+		if id < self.store.len() {
+			self.store[id] = initial;
+			self.store_smoothing[id] = coeff;
+		}
+	}
+
+	pub fn create_output(&mut self, from: Input) -> Result<usize, DuplicateOutputError> {
+		if let Some(existing) = self.output_node {
+			return Err(DuplicateOutputError { existing });
+		}
 		let id = self.add_node(
 			Node::Output(from)
 		);
 		self.output_node = Some(id);
-		id
+		Ok(id)
+	}
+
+	/// Sets the left and right channels returned by `sample_stereo`. Mono
+	/// `sample` calls get their average.
+	pub fn create_stereo_output(&mut self, left: Input, right: Input) -> Result<usize, DuplicateOutputError> {
+		if let Some(existing) = self.output_node {
+			return Err(DuplicateOutputError { existing });
+		}
+		let id = self.add_node(
+			Node::StereoOutput(left, right)
+		);
+		self.output_node = Some(id);
+		Ok(id)
+	}
+
+	/// Equal-power pan, `position` from -1 (left) to 1 (right). Returns the
+	/// left and right channel nodes.
+	pub fn create_pan(&mut self, input: Input, position: Input) -> (usize, usize) {
+		let left = self.add_node(
+			Node::Pan(input, position, Channel::Left)
+		);
+		let right = self.add_node(
+			Node::Pan(input, position, Channel::Right)
+		);
+		(left, right)
+	}
+
+	/// Pans `input` back and forth with a sine at `rate` Hz, `depth` being the
+	/// excursion either side of centre. Returns the left and right channel nodes.
+	pub fn create_auto_pan(&mut self, input: Input, rate: Input, depth: Input) -> (usize, usize) {
+		let lfo = self.create_sine(rate, depth);
+		self.create_pan(input, Input::Node(lfo))
+	}
+
+	/// Without a `seed` the stream is derived from the master seed and the
+	/// node's id, so a patch renders the same noise every time it's loaded.
+	pub fn create_noise(&mut self, kind: NoiseKind, amp: Input, seed: Option<u32>) -> usize {
+		let id = self.dead.last().cloned().unwrap_or(self.nodes.len());
+		let gen = match seed {
+			Some(seed) => NoiseGen::seeded(seed),
+			None => NoiseGen::derived(self.seed, id)
+		};
+		self.add_node(
+			Node::Noise(gen, kind, amp)
+		)
+	}
+
+	pub fn create_pink_noise(&mut self, amp: Input, seed: Option<u32>) -> usize {
+		self.create_noise(NoiseKind::Pink, amp, seed)
+	}
+
+	pub fn create_sine(&mut self, freq: Input, amp: Input) -> usize {
+		self.add_node(
+			Node::Sine(self.new_phase(), freq, amp)
+		)
+	}
+
+	pub fn create_square(&mut self, freq: Input, amp: Input) -> usize {
+		self.add_node(
+			Node::Square(self.new_phase(), freq, amp)
+		)
+	}
+
+	pub fn create_saw(&mut self, freq: Input, amp: Input) -> usize {
+		self.add_node(
+			Node::Saw(self.new_phase(), freq, amp)
+		)
+	}
+
+	pub fn create_triangle(&mut self, freq: Input, amp: Input) -> usize {
+		self.add_node(
+			Node::Triangle(self.new_phase(), freq, amp)
+		)
+	}
+
+	/// Saw with PolyBLEP correction at the reset, so it aliases far less
+	/// than `Saw` at high frequencies.
+	pub fn create_saw_bl(&mut self, freq: Input, amp: Input) -> usize {
+		self.add_node(
+			Node::SawBL(self.new_phase(), freq, amp)
+		)
+	}
+
+	/// Square with PolyBLEP correction at both edges.
+	pub fn create_square_bl(&mut self, freq: Input, amp: Input) -> usize {
+		self.add_node(
+			Node::SquareBL(self.new_phase(), freq, amp)
+		)
+	}
+
+	/// `shape` morphs through sine (0), triangle (1), saw (2) and square (3),
+	/// crossfading between neighbours on fractional values.
+	pub fn create_osc(&mut self, freq: Input, amp: Input, shape: Input) -> usize {
+		self.add_node(
+			Node::Osc(self.new_phase(), freq, amp, shape)
+		)
+	}
+
+	/// Partial `k` of `partial_amps` plays at `fundamental * (k + 1)`. The amplitudes
+	/// are normalized so the sum of all partials stays within -1..1.
+	pub fn create_additive(&mut self, fundamental: Input, partial_amps: Vec<f32>) -> usize {
+		let total: f32 = partial_amps.iter().map(|a| a.abs()).sum();
+		let amps = if total > 0.0 {
+			partial_amps.iter().map(|a| a / total).collect()
+		} else {
+			partial_amps
+		};
+		self.add_node(
+			Node::Additive(self.new_phase(), fundamental, amps)
+		)
+	}
+
+	/// Adds a single-cycle table for `Wavetable` nodes to play, returning its id.
+	/// Records the file a `Wave` or `Convolve` node was loaded from, so
+	/// `to_source` can write it back out.
+	pub fn set_path(&mut self, id: usize, path: &str) {
+		self.paths.insert(id, path.to_owned());
+	}
+
+	pub fn load_wavetable(&mut self, samples: Vec<f32>) -> usize {
+		self.wavetables.push(samples);
+		self.wavetables.len() - 1
+	}
+
+	/// Plays the table from `load_wavetable` once per cycle, interpolating
+	/// linearly between samples. Empty tables are rejected.
+	pub fn create_wavetable(&mut self, freq: Input, amp: Input, table_id: usize) -> Result<usize, GraphError> {
+		match self.wavetables.get(table_id) {
+			Some(t) if !t.is_empty() => {},
+			_ => return Err(GraphError::MissingWavetable(table_id))
+		}
+		self.check_input(freq)?;
+		self.check_input(amp)?;
+		Ok(self.add_node(
+			Node::Wavetable(self.new_phase(), freq, amp, table_id)
+		))
+	}
+
+	/// Unipolar sine LFO sweeping `offset..offset + depth`.
+	pub fn create_lfo(&mut self, freq: Input, depth: Input, offset: Input) -> usize {
+		self.add_node(
+			Node::LFO(self.new_phase(), freq, depth, offset, false)
+		)
+	}
+
+	/// Bipolar sine LFO swinging `depth` either side of `offset`.
+	pub fn create_lfo_bi(&mut self, freq: Input, depth: Input, offset: Input) -> usize {
+		self.add_node(
+			Node::LFO(self.new_phase(), freq, depth, offset, true)
+		)
+	}
+
+	pub fn create_pulse_lfo(&mut self, rate: Input, width: Input) -> usize {
+		self.add_node(
+			Node::PulseLFO(self.new_phase(), rate, width)
+		)
+	}
+
+	/// Unipolar sine locked to the tempo grid, one cycle every `division` beats.
+	pub fn create_sync_lfo(&mut self, division: Input) -> usize {
+		self.add_node(
+			Node::SyncLFO(division)
+		)
 	}
 
-	pub fn create_sine(&mut self, freq: Input, amp: Input) -> usize {
+	/// Gate that is high for the first half of every `division` beats.
+	pub fn create_clock(&mut self, division: Input) -> usize {
 		self.add_node(
-			Node::Sine(Phase::new(PI * 2.0, self.sample_rate), freq, amp)
+			Node::Clock(division)
 		)
 	}
 
-	pub fn create_square(&mut self, freq: Input, amp: Input) -> usize {
+	/// Holds the value of `signal` from the last time `trigger` rose above 0.
+	pub fn create_sample_hold(&mut self, signal: Input, trigger: Input) -> usize {
 		self.add_node(
-			Node::Square(Phase::new(PI * 2.0, self.sample_rate), freq, amp)
+			Node::SampleHold(signal, trigger, Holder::new())
 		)
 	}
 
-	pub fn create_saw(&mut self, freq: Input, amp: Input) -> usize {
+	/// Frequency of the note played by `voice` on the live MIDI input.
+	pub fn create_midi_freq(&mut self, voice: usize) -> usize {
+		if voice >= self.midi.voices() {
+			self.midi.set_voices(voice + 1);
+		}
 		self.add_node(
-			Node::Saw(Phase::new(PI * 2.0, self.sample_rate), freq, amp)
+			Node::MidiFreq(voice)
 		)
 	}
 
-	pub fn create_triangle(&mut self, freq: Input, amp: Input) -> usize {
+	/// 1 while `voice` holds a note on the live MIDI input, otherwise 0.
+	pub fn create_midi_gate(&mut self, voice: usize) -> usize {
+		if voice >= self.midi.voices() {
+			self.midi.set_voices(voice + 1);
+		}
 		self.add_node(
-			Node::Triangle(Phase::new(PI * 2.0, self.sample_rate), freq, amp)
+			Node::MidiGate(voice)
 		)
 	}
 
-	pub fn create_lfo(&mut self, freq: Input) -> usize {
+	/// Maps `sample` linearly from one range to another. A zero-width source
+	/// range maps everything to `to_min`; `clamp` keeps the result inside
+	/// the target range.
+	pub fn create_map(&mut self, sample: Input, from_min: f32, from_max: f32, to_min: f32, to_max: f32, clamp: bool) -> usize {
 		self.add_node(
-			Node::LFO(Phase::new(PI * 2.0, self.sample_rate), freq)
+			Node::Map(sample, from_min, from_max, to_min, to_max, clamp)
 		)
 	}
 
-	pub fn create_map(&mut self, sample: Input, from_min: f32, from_max: f32, to_min: f32, to_max: f32) -> usize {
+	/// Maps a bipolar -1..1 signal to 0..1.
+	pub fn create_unipolar(&mut self, input: Input) -> usize {
 		self.add_node(
-			Node::Map(sample, from_min, from_max, to_min, to_max)
+			Node::Unipolar(input)
+		)
+	}
+
+	/// Maps a unipolar 0..1 signal to -1..1.
+	pub fn create_bipolar(&mut self, input: Input) -> usize {
+		self.add_node(
+			Node::Bipolar(input)
 		)
 	}
 
@@ -159,9 +1667,35 @@ impl NodeGraph {
 		)
 	}
 
-	pub fn create_writer(&mut self, id: usize, value: Input) -> usize {
+	/// Division by zero outputs 0.
+	pub fn create_div(&mut self, a: Input, b: Input) -> usize {
 		self.add_node(
+			Node::Div(a, b)
+		)
+	}
+
+	pub fn create_writer(&mut self, id: usize, value: Input) -> Result<usize, GraphError> {
+		if id >= self.store.len() {
+			return Err(GraphError::InvalidStore(id));
+		}
+		self.check_input(value)?;
+		Ok(self.add_node(
 			Node::Writer(id, value)
+		))
+	}
+
+	fn check_input(&self, input: Input) -> Result<(), GraphError> {
+		match input {
+			Input::Store(id) if id >= self.store.len() => Err(GraphError::InvalidStore(id)),
+			Input::Node(id) if id >= self.nodes.len() || self.dead.contains(&id) => Err(GraphError::InvalidNode(id)),
+			_ => Ok(())
+		}
+	}
+
+	/// Soft clips `input` with tanh so it never exceeds `threshold`.
+	pub fn create_clip(&mut self, input: Input, threshold: Input) -> usize {
+		self.add_node(
+			Node::Clip(input, threshold)
 		)
 	}
 
@@ -171,74 +1705,536 @@ impl NodeGraph {
 		)
 	}
 
-	pub fn delete_node(&mut self, id: usize) -> Result<(), &str> {
-		if self.dead.contains(&id) {
+	/// Mode 0 is ring modulation (bipolar modulator), mode 1 is classic AM
+	/// (unipolar modulator). `depth` blends from the dry carrier.
+	pub fn create_am(&mut self, carrier: Input, modulator: Input, depth: Input, mode: u8) -> usize {
+		self.add_node(
+			Node::AM(carrier, modulator, depth, mode)
+		)
+	}
+
+	/// `cutoff` is clamped below Nyquist, `resonance` is the filter's Q.
+	pub fn create_filter(&mut self, input: Input, cutoff: Input, resonance: Input, mode: FilterMode) -> usize {
+		self.add_node(
+			Node::Filter(Svf::new(), mode, input, cutoff, resonance)
+		)
+	}
+
+	pub fn create_convolve(&mut self, source: Input, ir: &[f32]) -> usize {
+		self.add_node(
+			Node::Convolve(source, Convolver::new(ir))
+		)
+	}
+
+	/// Convolves each channel of a stereo pair with `ir` separately.
+	pub fn create_stereo_convolve(&mut self, left: Input, right: Input, ir: &[f32]) -> (usize, usize) {
+		let l = self.create_convolve(left, ir);
+		let r = self.create_convolve(right, ir);
+		(l, r)
+	}
+
+	pub fn create_period_samples(&mut self, freq: Input) -> usize {
+		self.add_node(
+			Node::PeriodSamples(freq)
+		)
+	}
+
+	/// Length of one cycle of `freq` in samples, or 0 for non-positive frequencies.
+	pub fn period_samples(&self, freq: f32) -> f32 {
+		if freq > 0.0 {
+			self.sample_rate as f32 / freq
+		} else {
+			0.0
+		}
+	}
+
+	pub fn create_auto_gain(&mut self, source: Input, target_rms: f32, time_ms: f32) -> usize {
+		self.add_node(
+			Node::AutoGain(source, target_rms, Leveler::new(time_ms, self.sample_rate))
+		)
+	}
+
+	/// Echo with up to 4 seconds of delay. `feedback` is kept below 1 and
+	/// `mix` blends from dry (0) to fully delayed (1).
+	pub fn create_delay(&mut self, input: Input, time_secs: Input, feedback: Input, mix: Input) -> usize {
+		self.add_node(
+			Node::Delay(input, time_secs, feedback, mix, DelayLine::new(self.sample_rate))
+		)
+	}
+
+	/// A delay per channel, each with its own line, so a stereo pair keeps
+	/// its left and right apart instead of being summed to mono.
+	pub fn create_stereo_delay(&mut self, left: Input, right: Input, time_secs: Input, feedback: Input, mix: Input) -> (usize, usize) {
+		let l = self.create_delay(left, time_secs, feedback, mix);
+		let r = self.create_delay(right, time_secs, feedback, mix);
+		(l, r)
+	}
+
+	/// Band-pass whose cutoff rises from `base_cutoff` by up to four octaves
+	/// as the envelope of `source`, scaled by `sensitivity`, reaches 1.
+	pub fn create_auto_wah(&mut self, source: Input, sensitivity: Input, base_cutoff: Input) -> usize {
+		self.add_node(
+			Node::AutoWah(source, sensitivity, base_cutoff, Wah::new(self.sample_rate))
+		)
+	}
+
+	/// Tilts the spectrum around 700 Hz. `amount` goes from -1 (dark) to 1
+	/// (bright), up to 6 dB each side.
+	pub fn create_tilt(&mut self, source: Input, amount: Input) -> usize {
+		self.add_node(
+			Node::Tilt(source, amount, Tilter::new(self.sample_rate))
+		)
+	}
+
+	/// Follows the fundamental frequency of `source`, in Hz. The estimate
+	/// updates every 512 samples once the 2048-sample window has filled.
+	pub fn create_pitch_follow(&mut self, source: Input) -> usize {
+		self.add_node(
+			Node::PitchFollow(source, PitchTracker::new(self.sample_rate))
+		)
+	}
+
+	pub fn create_freeze(&mut self, source: Input, hold: Input) -> usize {
+		self.add_node(
+			Node::Freeze(source, hold, Freezer::new())
+		)
+	}
+
+	/// Deep-copies `template` and every node feeding it `count` times,
+	/// returning the id of each copy. Copies get their own oscillator and
+	/// filter state but share stores, and play the MIDI voices after the
+	/// template's.
+	pub fn spawn_voices(&mut self, template: usize, count: usize) -> Result<Vec<usize>, CycleError> {
+		let mut sub = Vec::new();
+		let mut stack = vec![template];
+		while let Some(id) = stack.pop() {
+			if sub.contains(&id) {
+				continue;
+			}
+			sub.push(id);
+			for input in self.nodes[id].inputs() {
+				if let Input::Node(from) = input {
+					stack.push(from);
+				}
+			}
+		}
+		sub.sort();
+
+		let span = sub.iter()
+			.filter_map(|id| match self.nodes[*id] {
+				Node::MidiFreq(v) | Node::MidiGate(v) => Some(v + 1),
+				_ => None
+			})
+			.max()
+			.unwrap_or(0);
+
+		let mut voices = Vec::with_capacity(count);
+		for k in 1..=count {
+			let mut ids = HashMap::new();
+			for id in sub.iter() {
+				let mut node = self.nodes[*id].clone();
+				if let Node::MidiFreq(v) | Node::MidiGate(v) = &mut node {
+					*v += k * span;
+				}
+				let copy = self.add_node(node);
+				if let Some(path) = self.paths.get(id).cloned() {
+					self.paths.insert(copy, path);
+				}
+				ids.insert(*id, copy);
+			}
+			for id in ids.values() {
+				for input in self.nodes[*id].inputs_mut() {
+					if let Input::Node(from) = input {
+						*from = ids[from];
+					}
+				}
+			}
+			voices.push(ids[&template]);
+		}
+		self.update_order()?;
+
+		let needed = span * (count + 1);
+		if needed > self.midi.voices() {
+			self.midi.set_voices(needed);
+		}
+		Ok(voices)
+	}
+
+	pub fn delete_node(&mut self, id: usize) -> Result<(), &'static str> {
+		if id >= self.nodes.len() || self.dead.contains(&id) {
 			return Err("Node doesn't exist");
 		}
 		self.nodes[id] = Node::Null;
+		self.outputs[id] = 0.0;
+		if self.output_node == Some(id) {
+			self.output_node = None;
+		}
 		self.dead.push(id);
+		self.constraints.retain(|&(a, b)| a != id && b != id);
+		self.paths.remove(&id);
+		Ok(())
+	}
+
+	/// Forces `after` to be evaluated after `before` in every `sample()` call.
+	pub fn force_order(&mut self, before: usize, after: usize) -> Result<(), &'static str> {
+		if before >= self.nodes.len() || after >= self.nodes.len() {
+			return Err("Node doesn't exist");
+		}
+		self.constraints.push((before, after));
+		if self.update_order().is_err() {
+			self.constraints.pop();
+			return Err("Order constraint conflicts with an existing one");
+		}
+		Ok(())
+	}
+
+	/// Evaluation order: every node after the nodes it reads from and after
+	/// any forced predecessors, otherwise in creation order. Store reads
+	/// aren't edges, so feedback through a Writer is fine.
+	pub fn topo_order(&self) -> Result<Vec<usize>, CycleError> {
+		let count = self.nodes.len();
+		let mut edges = vec![Vec::new(); count];
+		for &(before, after) in self.constraints.iter() {
+			edges[before].push(after);
+		}
+		for (id, n) in self.nodes.iter().enumerate() {
+			for input in n.inputs() {
+				if let Input::Node(src) = input {
+					if src < count {
+						edges[src].push(id);
+					}
+				}
+			}
+		}
+
+		let mut incoming = vec![0; count];
+		for &after in edges.iter().flatten() {
+			incoming[after] += 1;
+		}
+
+		let mut ready: BinaryHeap<Reverse<usize>> = (0..count)
+			.filter(|&id| incoming[id] == 0)
+			.map(Reverse)
+			.collect();
+
+		let mut order = Vec::with_capacity(count);
+		while let Some(Reverse(id)) = ready.pop() {
+			order.push(id);
+			for &after in edges[id].iter() {
+				incoming[after] -= 1;
+				if incoming[after] == 0 {
+					ready.push(Reverse(after));
+				}
+			}
+		}
+
+		if order.len() != count {
+			let nodes = (0..count).filter(|&id| incoming[id] > 0).collect();
+			return Err(CycleError { nodes });
+		}
+		Ok(order)
+	}
+
+	/// Recomputes the evaluation order from scratch. Needed after inputs are
+	/// rewired; adding nodes keeps the order valid on its own.
+	pub fn update_order(&mut self) -> Result<(), CycleError> {
+		self.order = self.topo_order()?;
 		Ok(())
 	}
 
+	/// Inputs still reading from deleted nodes, as (node, input index) pairs.
+	pub fn dangling_inputs(&self) -> Vec<(usize, usize)> {
+		let mut dangling = Vec::new();
+		for (node, n) in self.nodes.iter().enumerate() {
+			for (index, input) in n.inputs().into_iter().enumerate() {
+				if let Input::Node(src) = input {
+					if src >= self.nodes.len() || self.dead.contains(&src) {
+						dangling.push((node, index));
+					}
+				}
+			}
+		}
+		dangling
+	}
+
+	/// Every literal `Input::Value` in the graph, addressable by node and input index.
+	pub fn params(&self) -> Vec<Param> {
+		let mut params = Vec::new();
+		for (node, n) in self.nodes.iter().enumerate() {
+			for (index, input) in n.inputs().into_iter().enumerate() {
+				if let Input::Value(value) = input {
+					params.push(Param { node, index, value });
+				}
+			}
+		}
+		params
+	}
+
+	pub fn set_param(&mut self, node_id: usize, param_index: usize, value: f32) -> Result<(), &'static str> {
+		let node = match self.nodes.get_mut(node_id) {
+			Some(n) => n,
+			None => return Err("Node doesn't exist")
+		};
+		match node.inputs_mut().into_iter().nth(param_index) {
+			Some(input @ &mut Input::Value(_)) => {
+				*input = Input::Value(value);
+				Ok(())
+			},
+			Some(_) => Err("Parameter isn't a literal"),
+			None => Err("Parameter doesn't exist")
+		}
+	}
+
+	/// Enables per-node-kind timing of `sample()`. Disabling clears the report.
+	pub fn set_profiling(&mut self, enabled: bool) {
+		self.profiling = enabled;
+		if !enabled {
+			self.profile.clear();
+		}
+	}
+
+	/// Accumulated time spent per node kind, most expensive first.
+	pub fn profile_report(&self) -> Vec<(String, Duration)> {
+		let mut report: Vec<(String, Duration)> = self.profile.iter()
+			.map(|(name, time)| (name.to_string(), *time))
+			.collect();
+		report.sort_by_key(|r| Reverse(r.1));
+		report
+	}
+
+	/// Whether the graph's output is a `StereoOutput` node.
+	pub fn is_stereo(&self) -> bool {
+		matches!(self.output_node.and_then(|id| self.nodes.get(id)), Some(Node::StereoOutput(..)))
+	}
+
 	pub fn sample(&mut self) -> f32 {
-		for (id, n) in self.nodes.iter_mut().enumerate() {
+		let (left, right) = self.sample_stereo();
+		(left + right) * 0.5
+	}
+
+	/// Evaluates the graph once. Without a `StereoOutput` node both channels
+	/// carry the mono output.
+	pub fn sample_stereo(&mut self) -> (f32, f32) {
+		let started = self.sample_budget.map(|_| Instant::now());
+		while let Some(&(at, store, value)) = self.scheduled.front() {
+			if at > self.samples_generated {
+				break;
+			}
+			if let Some(s) = self.store.get_mut(store) {
+				*s = value;
+			}
+			self.scheduled.pop_front();
+		}
+		let out = self.evaluate();
+		let ctx = InputContext {
+			outputs: &self.outputs,
+			store: &self.store
+		};
+		let (left, right) = match self.output_node.and_then(|id| self.nodes.get(id)) {
+			Some(Node::StereoOutput(l, r)) => (l.sample(ctx), r.sample(ctx)),
+			_ => (out, out)
+		};
+		let click = self.click_sample();
+		self.samples_generated += 1;
+		self.beats += f64::from(self.tempo) / 60.0 / f64::from(self.sample_rate);
+		if let (Some(started), Some(budget)) = (started, self.sample_budget) {
+			let elapsed = started.elapsed();
+			if elapsed > budget {
+				if self.budget_overruns == 0 {
+					println!("Warning: sample() took {:?}, over the {:?} budget.", elapsed, budget);
+				}
+				self.budget_overruns += 1;
+			}
+		}
+		let (left, right) = (left + click, right + click);
+		if left.is_nan() || right.is_nan() {
+			self.nan_frames += 1;
+		}
+		if self.master_clip {
+			let clamp = |s: f32| if s.is_nan() { 0.0 } else { s.clamp(-1.0, 1.0) };
+			(clamp(left), clamp(right))
+		} else {
+			(left, right)
+		}
+	}
+
+	fn evaluate(&mut self) -> f32 {
+		for &id in self.order.iter() {
+			let n = &mut self.nodes[id];
 			let outputs = &self.outputs;
 			let store = &self.store;
 			let ctx = InputContext {
 				outputs, store
 			};
+			let start = if self.profiling { Some(Instant::now()) } else { None };
 			self.outputs[id] = match n {
 				Node::Sine(p, freq, amp) => {
-					p.advance(freq.sample(ctx)).sin() * amp.sample(ctx)
+					osc_shape(0, p.advance(freq.sample(ctx))) * amp.sample(ctx)
 				},
 				Node::Square(p, freq, amp) => {
-					(if p.advance(freq.sample(ctx)) > 0.5 { 1.0 } else { -1.0 }) * amp.sample(ctx)
+					osc_shape(3, p.advance(freq.sample(ctx))) * amp.sample(ctx)
 				},
 				Node::Saw(p, freq, amp) => {
-					(p.advance(freq.sample(ctx)) * 2.0 - 1.0) * amp.sample(ctx)
+					osc_shape(2, p.advance(freq.sample(ctx))) * amp.sample(ctx)
 				},
 				Node::Triangle(p, freq, amp) => {
-					let a = amp.sample(ctx);
+					osc_shape(1, p.advance(freq.sample(ctx))) * amp.sample(ctx)
+				},
+				Node::SawBL(p, freq, amp) => {
+					let t = p.advance(freq.sample(ctx)) / (PI * 2.0);
+					let dt = p.increment() / (PI * 2.0);
+					(t * 2.0 - 1.0 - poly_blep(t, dt)) * amp.sample(ctx)
+				},
+				Node::SquareBL(p, freq, amp) => {
+					let t = p.advance(freq.sample(ctx)) / (PI * 2.0);
+					let dt = p.increment() / (PI * 2.0);
+					let naive = if t < 0.5 { 1.0 } else { -1.0 };
+					(naive + poly_blep(t, dt) - poly_blep((t + 0.5) % 1.0, dt)) * amp.sample(ctx)
+				},
+				Node::Wavetable(p, freq, amp, table) => {
+					let t = &self.wavetables[*table];
+					let pos = p.advance(freq.sample(ctx)) / (PI * 2.0) * t.len() as f32;
+					let i = pos as usize % t.len();
+					let f = pos.fract();
+					(t[i] * (1.0 - f) + t[(i + 1) % t.len()] * f) * amp.sample(ctx)
+				},
+				Node::Osc(p, freq, amp, shape) => {
 					let ph = p.advance(freq.sample(ctx));
-					if ph < PI {
-						(-1.0 + (2.0 / PI) * ph) * a
-					} else {
-						(3.0 - (2.0 / PI) * ph) * a
-					}
+					let sh = shape.sample(ctx).max(0.0).min((OSC_SHAPES - 1) as f32);
+					let lo = sh.floor() as usize;
+					let hi = (lo + 1).min(OSC_SHAPES - 1);
+					let f = sh - lo as f32;
+					(osc_shape(lo, ph) * (1.0 - f) + osc_shape(hi, ph) * f) * amp.sample(ctx)
+				},
+				Node::Noise(gen, kind, amp) => {
+					let n = match kind {
+						NoiseKind::White => gen.white(),
+						NoiseKind::Pink => gen.pink()
+					};
+					n * amp.sample(ctx)
+				},
+				Node::Additive(p, freq, amps) => {
+					let f = freq.sample(ctx);
+					let ph = p.advance(f);
+					let nyquist = self.sample_rate as f32 / 2.0;
+					amps.iter().enumerate()
+						.take_while(|(k, _)| f * ((*k + 1) as f32) < nyquist)
+						.map(|(k, a)| (ph * (k + 1) as f32).sin() * a)
+						.sum()
 				},
 				Node::Output(input) => input.sample(ctx),
-				Node::LFO(p, freq) => p.advance(freq.sample(ctx)).sin() * 0.5 + 0.5,
-				Node::Map(sample, from_min, from_max, to_min, to_max) => {
+				Node::StereoOutput(left, right) => (left.sample(ctx) + right.sample(ctx)) * 0.5,
+				Node::Pan(input, position, channel) => {
+					let angle = (position.sample(ctx).clamp(-1.0, 1.0) + 1.0) * PI / 4.0;
+					input.sample(ctx) * match channel {
+						Channel::Left => angle.cos(),
+						Channel::Right => angle.sin()
+					}
+				},
+				Node::LFO(p, freq, depth, offset, bipolar) => {
+					let s = p.advance(freq.sample(ctx)).sin();
+					let s = if *bipolar { s } else { s * 0.5 + 0.5 };
+					s * depth.sample(ctx) + offset.sample(ctx)
+				},
+				Node::PulseLFO(p, rate, width) => {
+					let ph = p.advance(rate.sample(ctx)) / (PI * 2.0);
+					if ph < width.sample(ctx) { 1.0 } else { 0.0 }
+				},
+				Node::SyncLFO(division) => {
+					let ph = beat_phase(self.beats, division.sample(ctx));
+					(ph * PI * 2.0).sin() * 0.5 + 0.5
+				},
+				Node::Clock(division) => {
+					let ph = beat_phase(self.beats, division.sample(ctx));
+					if ph < 0.5 { 1.0 } else { 0.0 }
+				},
+				Node::MidiFreq(voice) => self.midi.freq(*voice),
+				Node::MidiGate(voice) => self.midi.gate(*voice),
+				Node::SampleHold(signal, trigger, holder) => {
+					holder.process(signal.sample(ctx), trigger.sample(ctx))
+				},
+				Node::Map(sample, from_min, from_max, to_min, to_max, clamp) => {
 					let s = sample.sample(ctx);
-					let norm = (s - *from_min) / (*from_max - *from_min);
-					norm * (*to_max - *to_min) + *to_min
+					let width = *from_max - *from_min;
+					let norm = if width != 0.0 { (s - *from_min) / width } else { 0.0 };
+					let v = norm * (*to_max - *to_min) + *to_min;
+					if *clamp {
+						v.max(to_min.min(*to_max)).min(to_min.max(*to_max))
+					} else {
+						v
+					}
 				},
+				Node::Unipolar(input) => input.sample(ctx) * 0.5 + 0.5,
+				Node::Bipolar(input) => input.sample(ctx) * 2.0 - 1.0,
 				Node::Add(a, b) => a.sample(ctx) + b.sample(ctx),
 				Node::Sub(a, b) => a.sample(ctx) - b.sample(ctx),
 				Node::Mul(a, b) => a.sample(ctx) * b.sample(ctx),
+				Node::Div(a, b) => {
+					let d = b.sample(ctx);
+					if d != 0.0 { a.sample(ctx) / d } else { 0.0 }
+				},
 				Node::Writer(id, value) => {
 					let s = value.sample(ctx);
-					self.store[*id] = s;
-					s
+					let k = self.store_smoothing[*id];
+					self.store[*id] += (s - self.store[*id]) * k;
+					self.store[*id]
+				},
+				Node::Clip(input, threshold) => {
+					let t = threshold.sample(ctx).abs().max(1e-6);
+					t * (input.sample(ctx) / t).tanh()
 				},
 				Node::Mix(a, b, f) => {
 					let sa = a.sample(ctx);
 					let sb = b.sample(ctx);
 					(1.0 - *f) * sa + sb * *f
 				},
+				Node::AM(carrier, modulator, depth, mode) => {
+					let m = match *mode {
+						0 => modulator.sample(ctx),
+						_ => modulator.sample(ctx) * 0.5 + 0.5
+					};
+					let d = depth.sample(ctx);
+					carrier.sample(ctx) * (1.0 - d + d * m)
+				},
+				Node::Filter(svf, mode, input, cutoff, q) => {
+					let x = input.sample(ctx);
+					let (low, _, high) = svf.process(x, cutoff.sample(ctx), q.sample(ctx), self.sample_rate);
+					match mode {
+						FilterMode::LowPass => low,
+						FilterMode::HighPass => high
+					}
+				},
+				Node::AutoWah(source, sensitivity, base, wah) => {
+					let x = source.sample(ctx);
+					wah.process(x, sensitivity.sample(ctx), base.sample(ctx), self.sample_rate)
+				},
+				Node::Convolve(source, conv) => conv.process(source.sample(ctx)),
+				Node::Delay(input, time, feedback, mix, line) => {
+					let delay = time.sample(ctx) * self.sample_rate as f32;
+					line.process(input.sample(ctx), delay, feedback.sample(ctx), mix.sample(ctx), self.delay_interp)
+				},
+				Node::AutoGain(source, target, lev) => lev.process(source.sample(ctx), *target),
+				Node::Tilt(source, amount, tilt) => tilt.process(source.sample(ctx), amount.sample(ctx)),
+				Node::PitchFollow(source, tracker) => tracker.process(source.sample(ctx)),
+				Node::Freeze(source, hold, fr) => {
+					let x = source.sample(ctx);
+					fr.process(x, hold.sample(ctx) > 0.0)
+				},
+				Node::PeriodSamples(freq) => {
+					let f = freq.sample(ctx);
+					if f > 0.0 { self.sample_rate as f32 / f } else { 0.0 }
+				},
 				_ => 0.0
 			};
+			if let Some(start) = start {
+				*self.profile.entry(n.name()).or_default() += start.elapsed();
+			}
 		}
-		if !self.nodes.is_empty() {
-			let out_node = self.output_node.unwrap_or(self.nodes.len() - 1);
-			self.outputs[out_node]
-		} else {
-			0.0
-		}
+		self.output_node.map_or(0.0, |id| self.outputs[id])
 	}
 
 	fn add_node(&mut self, n: Node) -> usize {
-		match self.dead.is_empty() {
+		let id = match self.dead.is_empty() {
 			true => {
 				self.nodes.push(n);
 				self.outputs.push(0.0);
@@ -247,8 +2243,375 @@ impl NodeGraph {
 			false => {
 				let id = self.dead.pop().unwrap();
 				self.nodes[id] = n;
+				self.order.retain(|&o| o != id);
 				id
 			}
+		};
+		// A new node only reads nodes that already exist, so running it last
+		// keeps the order valid without a full sort.
+		self.order.push(id);
+		id
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::parser::GraphLoader;
+
+	fn render_stereo(source: &str, samples: usize) -> Vec<(f32, f32)> {
+		let mut graph = GraphLoader::from_source(source).load().unwrap();
+		(0..samples).map(|_| graph.sample_stereo()).collect()
+	}
+
+	fn rms(samples: &[f32]) -> f32 {
+		(samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+	}
+
+	#[test]
+	fn auto_pan_sweeps_between_channels() {
+		let out = render_stereo("StereoOutput(AutoPan(Sine(440, 0.5), 1, 1))", 44100);
+		let window = |at: usize| {
+			let (l, r): (Vec<f32>, Vec<f32>) = out[at - 441..at + 441].iter().cloned().unzip();
+			(rms(&l), rms(&r))
+		};
+		let (l, r) = window(11025);
+		assert!(r > 0.3 && l < 0.02, "expected hard right at 0.25s, got L {} R {}", l, r);
+		let (l, r) = window(33075);
+		assert!(l > 0.3 && r < 0.02, "expected hard left at 0.75s, got L {} R {}", l, r);
+	}
+
+	fn noise_stream(mut gen: NoiseGen) -> Vec<f32> {
+		(0..64).map(|_| gen.white()).collect()
+	}
+
+	#[test]
+	fn noise_seeds_pick_the_stream() {
+		let out = render_stereo("StereoOutput(Noise(1, 42), Noise(1, 42))", 1024);
+		assert!(out.iter().all(|(l, r)| l == r));
+		assert!(out.iter().any(|&(l, _)| l != 0.0));
+
+		let out = render_stereo("StereoOutput(Noise(1, 42), Noise(1, 43))", 1024);
+		assert!(out.iter().all(|(l, r)| l != r));
+	}
+
+	#[test]
+	fn explicit_and_derived_seeds_never_collide() {
+		for id in 0..256 {
+			let explicit = noise_stream(NoiseGen::seeded(id as u32));
+			assert_ne!(explicit, noise_stream(NoiseGen::derived(0, id)));
+			assert_ne!(explicit, noise_stream(NoiseGen::derived(id as u32, id)));
+		}
+	}
+
+	#[test]
+	fn master_seed_changes_derived_streams_only() {
+		let a = render_stereo("@seed(1)\nStereoOutput(Noise(), Noise(1, 5))", 256);
+		let b = render_stereo("@seed(2)\nStereoOutput(Noise(), Noise(1, 5))", 256);
+		assert!(a.iter().zip(&b).all(|(a, b)| a.0 != b.0 && a.1 == b.1));
+
+		// Reseeding after the nodes exist re-derives their streams.
+		let mut graph = GraphLoader::from_source("StereoOutput(Noise(), Noise(1, 5))").load().unwrap();
+		graph.set_seed(1);
+		assert_eq!(a, (0..256).map(|_| graph.sample_stereo()).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn stereo_delay_keeps_channels_apart() {
+		let out = render_stereo("StereoOutput(Delay(Pan(Sine(440, 0.5), -1), 0.01, 0.5, 1))", 4410);
+		assert!(out.iter().all(|&(_, r)| r == 0.0));
+		assert!(out.iter().any(|&(l, _)| l.abs() > 0.1));
+
+		let mono = |freq: f32| {
+			let mut g = NodeGraph::new(44100);
+			let sine = g.create_sine(Input::Value(freq), Input::Value(0.5));
+			let delay = g.create_delay(Input::Node(sine), Input::Value(0.01), Input::Value(0.6), Input::Value(1.0));
+			g.create_output(Input::Node(delay)).unwrap();
+			(0..4410).map(|_| g.sample()).collect::<Vec<f32>>()
+		};
+		let mut g = NodeGraph::new(44100);
+		let l = g.create_sine(Input::Value(440.0), Input::Value(0.5));
+		let r = g.create_sine(Input::Value(1000.0), Input::Value(0.5));
+		let (dl, dr) = g.create_stereo_delay(Input::Node(l), Input::Node(r), Input::Value(0.01), Input::Value(0.6), Input::Value(1.0));
+		g.create_stereo_output(Input::Node(dl), Input::Node(dr)).unwrap();
+		let (left, right): (Vec<f32>, Vec<f32>) = (0..4410).map(|_| g.sample_stereo()).unzip();
+		assert_eq!(left, mono(440.0));
+		assert_eq!(right, mono(1000.0));
+	}
+
+	/// RMS difference between a delay line swept around 10 ms and the ideal
+	/// swept 3 kHz tone it should reproduce.
+	fn swept_delay_error(interp: DelayInterp) -> f64 {
+		let (rate, freq) = (44100.0, 3000.0);
+		let tone = |t: f64| (2.0 * std::f64::consts::PI * freq * t).sin();
+		let mut line = DelayLine::new(44100);
+		let mut error = 0.0;
+		for n in 0..22050 {
+			let t = f64::from(n) / rate;
+			let delay = 441.0 + 88.0 * (2.0 * std::f64::consts::PI * 3.0 * t).sin();
+			let y = line.process(tone(t) as f32, delay as f32, 0.0, 1.0, interp);
+			if n >= 1024 {
+				error += (f64::from(y) - tone(t - delay / rate)).powi(2);
+			}
+		}
+		(error / f64::from(22050 - 1024)).sqrt()
+	}
+
+	#[test]
+	fn cubic_delay_reads_are_cleaner_under_modulation() {
+		let linear = swept_delay_error(DelayInterp::Linear);
+		let cubic = swept_delay_error(DelayInterp::Cubic);
+		assert!(cubic * 8.0 < linear, "linear {} cubic {}", linear, cubic);
+	}
+
+	#[test]
+	fn no_output_node_is_silent() {
+		let mut g = NodeGraph::new(44100);
+		g.create_sine(Input::Value(440.0), Input::Value(0.5));
+		assert!((0..100).all(|_| g.sample() == 0.0));
+	}
+
+	#[test]
+	fn reused_node_ids_run_after_their_inputs() {
+		let mut g = NodeGraph::new(44100);
+		let first = g.create_sine(Input::Value(440.0), Input::Value(0.5));
+		let sine = g.create_sine(Input::Value(440.0), Input::Value(0.5));
+		g.delete_node(first).unwrap();
+		let clip = g.create_clip(Input::Node(sine), Input::Value(1.0));
+		assert_eq!(clip, first);
+		g.create_output(Input::Node(clip)).unwrap();
+		let mut fresh = NodeGraph::new(44100);
+		let sine = fresh.create_sine(Input::Value(440.0), Input::Value(0.5));
+		let clip = fresh.create_clip(Input::Node(sine), Input::Value(1.0));
+		fresh.create_output(Input::Node(clip)).unwrap();
+		assert!((0..100).all(|_| g.sample() == fresh.sample()));
+	}
+
+	#[test]
+	fn nans_are_counted_before_the_master_clip() {
+		let mut g = NodeGraph::new(44100);
+		let store = g.create_value_store();
+		g.create_output(Input::Store(store)).unwrap();
+		g.set_store(store, f32::NAN);
+		assert!((0..10).all(|_| g.sample() == 0.0));
+		assert_eq!(g.take_nan_frames(), 10);
+		assert_eq!(g.take_nan_frames(), 0);
+	}
+
+	#[test]
+	fn convolving_an_impulse_returns_the_ir() {
+		// Long enough to span several partitions.
+		let ir: Vec<f32> = (0..CONVOLVE_BLOCK * 3 + 17)
+			.map(|i| ((i * 7919) % 101) as f32 / 50.0 - 1.0)
+			.collect();
+		let mut conv = Convolver::new(&ir);
+		let out: Vec<f32> = (0..CONVOLVE_BLOCK * 6)
+			.map(|n| conv.process(if n == 0 { 1.0 } else { 0.0 }))
+			.collect();
+
+		assert!(out[..CONVOLVE_BLOCK].iter().all(|s| s.abs() < 1e-6));
+		for (i, h) in ir.iter().enumerate() {
+			let y = out[CONVOLVE_BLOCK + i];
+			assert!((y - h).abs() < 1e-4, "sample {}: {} != {}", i, y, h);
+		}
+		assert!(out[CONVOLVE_BLOCK + ir.len()..].iter().all(|s| s.abs() < 1e-4));
+	}
+
+	#[test]
+	fn set_param_drives_a_literal_amplitude() {
+		let mut graph = GraphLoader::from_source("Output(Sine(441, 0.5))").load().unwrap();
+		let sine = graph.output_node().and_then(|out| match graph.nodes[out] {
+			Node::Output(Input::Node(id)) => Some(id),
+			_ => None
+		}).unwrap();
+		let amp = graph.params().into_iter()
+			.find(|p| p.node == sine && p.value == 0.5)
+			.unwrap();
+
+		let peak = |g: &mut NodeGraph| (0..4410).map(|_| g.sample().abs()).fold(0.0, f32::max);
+		assert!((peak(&mut graph) - 0.5).abs() < 0.01);
+		graph.set_param(amp.node, amp.index, 0.2).unwrap();
+		assert!((peak(&mut graph) - 0.2).abs() < 0.01);
+		assert_eq!(graph.set_param(sine, 99, 1.0), Err("Parameter doesn't exist"));
+	}
+
+	#[test]
+	fn forced_order_lets_a_reader_see_this_samples_write() {
+		// The reader is created first, so by default it runs first and sees
+		// the store one sample late.
+		let build = || {
+			let mut g = NodeGraph::new(44100);
+			let store = g.create_value_store();
+			let reader = g.create_add(Input::Store(store), Input::Value(0.0));
+			let writer = g.create_writer(store, Input::Value(0.5)).unwrap();
+			g.create_output(Input::Node(reader)).unwrap();
+			(g, reader, writer)
+		};
+
+		let (mut g, ..) = build();
+		assert_eq!(g.sample(), 0.0);
+
+		let (mut g, reader, writer) = build();
+		g.force_order(writer, reader).unwrap();
+		assert_eq!(g.sample(), 0.5);
+		assert!(g.force_order(reader, writer).is_err());
+	}
+
+	#[test]
+	fn profile_lists_the_kinds_in_the_patch() {
+		let mut graph = GraphLoader::from_source("Output(LowPass(Saw(110, 0.5), 800, 1))").load().unwrap();
+		for _ in 0..64 {
+			graph.sample();
+		}
+		assert!(graph.profile_report().is_empty());
+
+		graph.set_profiling(true);
+		for _ in 0..4410 {
+			graph.sample();
+		}
+		let report = graph.profile_report();
+		let mut kinds: Vec<&str> = report.iter().map(|(name, _)| name.as_str()).collect();
+		kinds.sort();
+		assert_eq!(kinds, vec!["Filter", "Output", "Saw"]);
+		assert!(report.iter().all(|(_, time)| *time > Duration::from_secs(0)));
+
+		graph.set_profiling(false);
+		assert!(graph.profile_report().is_empty());
+	}
+
+	fn largest_step_off_the_edges(stepped: bool) -> f32 {
+		let mut g = NodeGraph::new(44100);
+		let shape = g.create_value_store();
+		let osc = g.create_osc(Input::Value(110.0), Input::Value(0.5), Input::Store(shape));
+		g.create_output(Input::Node(osc)).unwrap();
+
+		// Saw and square jump where the cycle wraps, square also half way;
+		// any other step would be the crossfade clicking.
+		let cycles = 110.0 / 44100.0;
+		let mut largest = 0.0f32;
+		let mut prev = g.sample();
+		for n in 1..44100 {
+			let sweep = 3.0 * n as f32 / 44100.0;
+			g.set_store(shape, if stepped { sweep.round() } else { sweep });
+			let y = g.sample();
+			let t = ((n + 1) as f64 * cycles).fract();
+			let edge = t.min(1.0 - t) < cycles * 2.0 || (t - 0.5).abs() < cycles * 2.0;
+			if !edge {
+				largest = largest.max((y - prev).abs());
+			}
+			prev = y;
+		}
+		largest
+	}
+
+	#[test]
+	fn osc_shape_sweep_is_click_free() {
+		let largest = largest_step_off_the_edges(false);
+		assert!(largest < 0.01, "sweep stepped by {}", largest);
+		assert!(largest_step_off_the_edges(true) > 0.1);
+	}
+
+	#[test]
+	fn tempo_synced_lfos_share_the_downbeat() {
+		// At 120 BPM a beat is 22050 samples and a bar 88200.
+		let out = render_stereo("Tempo(120)\nStereoOutput(SyncLFO(1), SyncLFO(0.5))", 88200 * 2 + 2);
+		for &bar in [0, 88200, 176400].iter() {
+			for &(lfo, next) in [(out[bar].0, out[bar + 1].0), (out[bar].1, out[bar + 1].1)].iter() {
+				assert!((lfo - 0.5).abs() < 1e-3, "bar at {} starts at {}", bar, lfo);
+				assert!(next > lfo, "bar at {} starts falling", bar);
+			}
+		}
+		let (slow, fast) = out[5512];
+		assert!(slow > 0.99 && (fast - 0.5).abs() < 1e-3, "{} {}", slow, fast);
+	}
+
+	// Per-sample phase increments across a step from 100 Hz to 1 kHz.
+	fn stepped_increments(declick: u32) -> Vec<f32> {
+		let mut phase = Phase::new(PI * 2.0, 44100);
+		phase.set_declick(declick);
+		(0..400).map(|n| {
+			phase.advance(if n < 200 { 100.0 } else { 1000.0 });
+			phase.increment()
+		}).collect()
+	}
+
+	#[test]
+	fn declick_eases_the_phase_increment() {
+		let (low, high) = (PI * 2.0 * 100.0 / 44100.0, PI * 2.0 * 1000.0 / 44100.0);
+		let largest_change = |inc: &[f32]| inc.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max);
+
+		let abrupt = stepped_increments(0);
+		assert!((largest_change(&abrupt) - (high - low)).abs() < 1e-6);
+
+		let eased = stepped_increments(32);
+		assert!((eased[199] - low).abs() < 1e-6);
+		assert!(largest_change(&eased) < (high - low) / 16.0, "increment jumped by {}", largest_change(&eased));
+		assert!(eased.windows(2).skip(199).all(|w| w[1] >= w[0]));
+		assert!((eased[399] - high).abs() < 0.01 * high);
+	}
+
+	#[test]
+	fn click_lands_on_every_beat() {
+		let mut graph = GraphLoader::from_source("Tempo(120)\nOutput(0.0)").load().unwrap();
+		graph.set_click(true);
+		let out: Vec<f32> = (0..88200 * 2).map(|_| graph.sample()).collect();
+
+		// First non-silent sample after at least a beat's worth of gap.
+		let mut onsets = Vec::new();
+		let mut last = None;
+		for (n, s) in out.iter().enumerate() {
+			if *s != 0.0 {
+				if last.map_or(true, |l| n - l > 11025) {
+					onsets.push(n);
+				}
+				last = Some(n);
+			}
+		}
+		assert_eq!(onsets.len(), 8);
+		for (beat, onset) in onsets.iter().enumerate() {
+			assert!((*onset as isize - beat as isize * 22050).abs() <= 2, "beat {} clicked at {}", beat, onset);
+		}
+
+		let peak = |beat: usize| out[beat * 22050..beat * 22050 + 2205].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+		assert!(peak(0) > peak(1) * 1.5 && peak(4) > peak(5) * 1.5);
+		assert!(out[22050 + 2205..44100 - 1].iter().all(|&s| s == 0.0));
+	}
+
+	#[test]
+	fn pitch_follow_settles_on_the_input_frequency() {
+		let mut graph = GraphLoader::from_source("Output(PitchFollow(Sine(330, 0.5)))").load().unwrap();
+		graph.set_master_clip(false);
+		let out: Vec<f32> = (0..22050).map(|_| graph.sample()).collect();
+		for &hz in out[PITCH_WINDOW * 2..].iter() {
+			assert!((hz - 330.0).abs() < 1.0, "tracked {} Hz", hz);
 		}
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn deleting_the_source_silences_the_output() {
+		let mut g = NodeGraph::new(44100);
+		let sine = g.create_sine(Input::Value(441.0), Input::Value(0.5));
+		let out = g.create_output(Input::Node(sine)).unwrap();
+		assert!((0..100).any(|_| g.sample() != 0.0));
+
+		g.delete_node(sine).unwrap();
+		assert_eq!(g.dangling_inputs(), vec![(out, 0)]);
+		assert!((0..100).all(|_| g.sample() == 0.0));
+
+		g.delete_node(out).unwrap();
+		assert_eq!(g.output_node(), None);
+		assert!(g.delete_node(out).is_err());
+	}
+
+	#[test]
+	fn master_clip_keeps_a_hot_constant_in_range() {
+		let mut g = GraphLoader::from_source("Output(4.0)").load().unwrap();
+		assert!((0..10).all(|_| g.sample() == 1.0));
+		g.set_master_clip(false);
+		assert_eq!(g.sample(), 4.0);
+
+		let mut g = GraphLoader::from_source("Output(Clip(Sine(441, 4), 0.8))").load().unwrap();
+		g.set_master_clip(false);
+		let peak = (0..4410).map(|_| g.sample().abs()).fold(0.0, f32::max);
+		assert!(peak < 0.8 && peak > 0.79, "soft clip peaked at {}", peak);
+	}
+}
@@ -0,0 +1,103 @@
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use crate::node::NodeGraph;
+
+const STORE_PREFIX: &str = "/store/";
+
+// OSC strings are null-terminated and padded to a multiple of 4 bytes.
+fn read_string(data: &[u8], pos: &mut usize) -> Option<String> {
+	let start = *pos;
+	let len = data.get(start..)?.iter().position(|b| *b == 0)?;
+	let s = String::from_utf8(data[start..start + len].to_vec()).ok()?;
+	*pos = start + (len + 4) / 4 * 4;
+	Some(s)
+}
+
+fn read_bytes(data: &[u8], pos: usize) -> Option<[u8; 4]> {
+	let b = data.get(pos..pos + 4)?;
+	Some([b[0], b[1], b[2], b[3]])
+}
+
+/// Parses a `/store/<name> <float>` message into the store name and value.
+/// Integer arguments are accepted too.
+pub fn parse_message(data: &[u8]) -> Option<(String, f32)> {
+	let mut pos = 0;
+	let address = read_string(data, &mut pos)?;
+	let tags = read_string(data, &mut pos)?;
+	if !address.starts_with(STORE_PREFIX) || address.len() == STORE_PREFIX.len() {
+		return None;
+	}
+
+	let bytes = read_bytes(data, pos)?;
+	let value = match tags.as_bytes().get(0..2)? {
+		b",f" => f32::from_be_bytes(bytes),
+		b",i" => i32::from_be_bytes(bytes) as f32,
+		_ => return None
+	};
+	Some((address[STORE_PREFIX.len()..].to_owned(), value))
+}
+
+/// Writes `value` into the store named `name`, returning its id.
+pub fn apply(graph: &mut NodeGraph, name: &str, value: f32) -> Option<usize> {
+	let id = graph.store_by_name(name)?;
+	graph.set_store(id, value);
+	Some(id)
+}
+
+/// Listens for OSC messages on `port`, forwarding well-formed store updates.
+pub fn spawn_server(port: u16) -> Result<Receiver<(String, f32)>, String> {
+	let socket = UdpSocket::bind(("0.0.0.0", port)).map_err(|e| format!("OSC: {}", e))?;
+	let (tx, rx) = mpsc::channel();
+	thread::spawn(move || {
+		let mut buf = [0u8; 1024];
+		while let Ok(len) = socket.recv(&mut buf) {
+			match parse_message(&buf[..len]) {
+				Some(msg) => {
+					if tx.send(msg).is_err() {
+						break;
+					}
+				},
+				None => println!("Warning: Ignoring malformed OSC message.")
+			}
+		}
+	});
+	Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::parser::GraphLoader;
+
+	fn message(address: &str, tags: &str, arg: [u8; 4]) -> Vec<u8> {
+		let mut data = Vec::new();
+		for s in [address, tags].iter() {
+			data.extend_from_slice(s.as_bytes());
+			data.resize((data.len() + 4) / 4 * 4, 0);
+		}
+		data.extend_from_slice(&arg);
+		data
+	}
+
+	#[test]
+	fn store_messages_reach_the_named_store() {
+		let mut loader = GraphLoader::from_source("Cutoff = CreateStore(0)\nLevel = CreateStore(0)\nOutput(Level)");
+		let mut graph = loader.load().unwrap();
+
+		let msg = message("/store/Level", ",f", 0.25f32.to_be_bytes());
+		let (name, value) = parse_message(&msg).unwrap();
+		assert_eq!((name.as_str(), value), ("Level", 0.25));
+		assert_eq!(apply(&mut graph, &name, value), loader.store_id("Level"));
+		assert_eq!(graph.sample(), 0.25);
+
+		let msg = message("/store/Cutoff", ",i", 800i32.to_be_bytes());
+		assert_eq!(parse_message(&msg), Some(("Cutoff".to_owned(), 800.0)));
+		assert_eq!(apply(&mut graph, "Missing", 1.0), None);
+		assert_eq!(parse_message(&message("/other/Level", ",f", [0; 4])), None);
+		assert_eq!(parse_message(&message("/store/Level", ",s", [0; 4])), None);
+		assert_eq!(parse_message(&msg[..msg.len() - 2]), None);
+	}
+}
@@ -1,23 +1,34 @@
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::fs;
 
-use crate::node::{ NodeGraph, Input };
+use crate::node::{ NodeGraph, Input, FilterMode, NoiseKind, DelayInterp };
+use crate::wav;
 
 struct Reader {
 	data: Vec<char>,
-	pos: usize
+	pos: usize,
+	line: usize,
+	col: usize
 }
 
 impl Reader {
 	pub fn new(input: Vec<char>) -> Reader {
 		Reader {
 			data: input,
-			pos: 0
+			pos: 0,
+			line: 1,
+			col: 1
 		}
 	}
 
+	/// Line and column (both from 1) of the next unconsumed character.
+	pub fn position(&self) -> (usize, usize) {
+		(self.line, self.col)
+	}
+
 	pub fn has_next(&self) -> bool {
 		self.pos < self.data.len()
 	}
@@ -34,19 +45,31 @@ impl Reader {
 		if self.pos >= self.data.len() {
 			return None;
 		}
+		let c = self.data[self.pos];
 		self.pos += 1;
-		Some(self.data[self.pos - 1])
+		if c == '\n' {
+			self.line += 1;
+			self.col = 1;
+		} else {
+			self.col += 1;
+		}
+		Some(c)
 	}
 
+	/// The character the next call to `next` will return, without consuming it.
 	pub fn peek(&self) -> Option<char> {
-		if self.pos >= self.data.len() {
-			return None;
-		}
-		Some(self.data[self.pos + 1])
+		self.data.get(self.pos).cloned()
 	}
 
 	pub fn step_back(&mut self) {
 		self.pos -= 1;
+		if self.data[self.pos] == '\n' {
+			self.line -= 1;
+			let start = self.data[..self.pos].iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1);
+			self.col = self.pos - start + 1;
+		} else {
+			self.col -= 1;
+		}
 	}
 
 	pub fn current(&self) -> char {
@@ -62,10 +85,16 @@ pub enum TokenType {
 	Unknown,
 	Identifier,
 	Number,
+	String,
+	Annotation,
 	LParen,
 	RParen,
 	Equals,
 	Comma,
+	Plus,
+	Minus,
+	Star,
+	Slash,
 	EOF
 }
 
@@ -73,24 +102,59 @@ pub enum TokenType {
 pub struct Token {
 	token_type: TokenType,
 	lexeme: String,
-	value: f32
+	value: f32,
+	pub line: usize,
+	pub col: usize
 }
 
 impl Token {
-	pub fn new(token_type: TokenType, lexeme: &str, value: f32) -> Token {
+	pub fn new(token_type: TokenType, lexeme: &str, value: f32, line: usize, col: usize) -> Token {
 		Token {
 			token_type,
 			lexeme: lexeme.to_owned(),
-			value
+			value,
+			line,
+			col
+		}
+	}
+}
+
+/// An error that stops a patch from loading, with the token it was found at
+/// when there is one.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+	pub message: String,
+	pub token: Option<Token>
+}
+
+impl ParseError {
+	pub fn new(message: String) -> ParseError {
+		ParseError { message, token: None }
+	}
+
+	pub fn at(message: String, token: &Token) -> ParseError {
+		ParseError { message, token: Some(token.clone()) }
+	}
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match &self.token {
+			Some(token) if !token.lexeme.is_empty() => {
+				write!(f, "{}:{}: {} (near \"{}\")", token.line, token.col, self.message, token.lexeme)
+			},
+			Some(token) => write!(f, "{}:{}: {}", token.line, token.col, self.message),
+			None => write!(f, "{}", self.message)
 		}
 	}
 }
 
-pub fn lex(input: &str) -> Vec<Token> {
+pub fn lex(input: &str) -> Result<Vec<Token>, ParseError> {
 	let mut sr = Reader::new(input.chars().collect());
 	let mut tokens = Vec::new();
 
 	while sr.has_next() {
+		let (line, col) = sr.position();
 		match sr.current() {
 			'a'...'z' | 'A'...'Z' | '_' => { // Identifier
 				let mut identifier = String::new();
@@ -98,34 +162,81 @@ pub fn lex(input: &str) -> Vec<Token> {
 					identifier.push(sr.current());
 					sr.next();
 				}
-				tokens.push(Token::new(TokenType::Identifier, identifier.as_str(), 0.0))
+				tokens.push(Token::new(TokenType::Identifier, identifier.as_str(), 0.0, line, col))
 			},
 			'-' | '0'...'9' | '.' => { // Number
 				let mut number = String::new();
-				while (sr.current().is_digit(10) || sr.current() == '.' || sr.current() == '-') && sr.has_next() {
+				if sr.current() == '-' {
+					// A sign only where a value can start, otherwise it's a minus.
+					let after_value = matches!(
+						tokens.last(),
+						Some(Token { token_type: TokenType::Number, .. }) |
+						Some(Token { token_type: TokenType::Identifier, .. }) |
+						Some(Token { token_type: TokenType::RParen, .. })
+					);
+					sr.next();
+					if after_value || !(sr.current().is_ascii_digit() || sr.current() == '.') || !sr.has_next() {
+						tokens.push(Token::new(TokenType::Minus, "-", 0.0, line, col));
+						continue;
+					}
+					number.push('-');
+				}
+				while (sr.current().is_ascii_digit() || sr.current() == '.') && sr.has_next() {
 					number.push(sr.current());
 					sr.next();
 				}
+				let token = Token::new(TokenType::Number, number.as_str(), 0.0, line, col);
 				let num = match number.parse::<f32>() {
 					Ok(n) => n,
-					Err(e) => panic!("Invalid number. {}", e)
+					Err(_) => return Err(ParseError::at("Invalid number.".to_owned(), &token))
 				};
-				tokens.push(Token::new(TokenType::Number, number.as_str(), num));
+				tokens.push(Token { value: num, ..token });
+			},
+			'"' => { // String
+				let mut string = String::new();
+				sr.next();
+				while sr.current() != '"' && sr.has_next() {
+					string.push(sr.current());
+					sr.next();
+				}
+				sr.next();
+				tokens.push(Token::new(TokenType::String, string.as_str(), 0.0, line, col));
+			},
+			'@' => { // Annotation
+				let mut name = String::new();
+				sr.next();
+				while sr.current().is_ascii_alphanumeric() && sr.has_next() {
+					name.push(sr.current());
+					sr.next();
+				}
+				tokens.push(Token::new(TokenType::Annotation, name.as_str(), 0.0, line, col));
 			},
 			'(' => {
-				tokens.push(Token::new(TokenType::LParen, "", 0.0));
+				tokens.push(Token::new(TokenType::LParen, "", 0.0, line, col));
 				sr.next();
 			},
 			')' => {
-				tokens.push(Token::new(TokenType::RParen, "", 0.0));
+				tokens.push(Token::new(TokenType::RParen, "", 0.0, line, col));
 				sr.next();
 			},
 			'=' => {
-				tokens.push(Token::new(TokenType::Equals, "", 0.0));
+				tokens.push(Token::new(TokenType::Equals, "", 0.0, line, col));
 				sr.next();
 			},
 			',' => {
-				tokens.push(Token::new(TokenType::Comma, "", 0.0));
+				tokens.push(Token::new(TokenType::Comma, "", 0.0, line, col));
+				sr.next();
+			},
+			'+' => {
+				tokens.push(Token::new(TokenType::Plus, "+", 0.0, line, col));
+				sr.next();
+			},
+			'*' => {
+				tokens.push(Token::new(TokenType::Star, "*", 0.0, line, col));
+				sr.next();
+			},
+			'/' => {
+				tokens.push(Token::new(TokenType::Slash, "/", 0.0, line, col));
 				sr.next();
 			},
 			' ' | '\n' | '\t' | '\r' => { sr.next(); },
@@ -135,24 +246,29 @@ pub fn lex(input: &str) -> Vec<Token> {
 				}
 			},
 			_ => {
-				tokens.push(Token::new(TokenType::Unknown, "", 0.0));
+				tokens.push(Token::new(TokenType::Unknown, &sr.current().to_string(), 0.0, line, col));
 				sr.next();
 			}
 		}
 	}
-	tokens.push(Token::new(TokenType::EOF, "", 0.0));
+	let (line, col) = sr.position();
+	tokens.push(Token::new(TokenType::EOF, "", 0.0, line, col));
 
 	// println!("{:#?}", tokens);
 
-	tokens
+	Ok(tokens)
 }
 
 #[derive(Debug, Clone)]
 pub enum Expr {
 	Literal(f32),
+	Str(String),
 	Identifier(String),
 	Assign(Box<Expr>, Box<Expr>),
-	Call(String, Vec<Expr>),
+	/// Function name, arguments and the token the call starts at.
+	Call(String, Vec<Expr>, Token),
+	Annotate(Box<Expr>, String, Vec<Expr>, Token),
+	Directive(String, Vec<Expr>, Token),
 	Program(Vec<Expr>)
 }
 
@@ -163,12 +279,12 @@ pub struct Parser {
 }
 
 impl Parser {
-	pub fn new(input: &str) -> Parser {
-		Parser {
-			tokens: lex(input),
-			unknown_token: Token::new(TokenType::Unknown, "", 0.0),
+	pub fn new(input: &str) -> Result<Parser, ParseError> {
+		Ok(Parser {
+			tokens: lex(input)?,
+			unknown_token: Token::new(TokenType::Unknown, "", 0.0, 0, 0),
 			pos: 0
-		}
+		})
 	}
 
 	fn prev(&self) -> &Token {
@@ -201,72 +317,148 @@ impl Parser {
 		}
 	}
 
-	fn expect(&mut self, tt: TokenType) -> bool {
+	fn expect(&mut self, tt: TokenType) -> Result<(), ParseError> {
 		if self.accept(tt) {
-			true
+			Ok(())
+		} else if self.peek().token_type == TokenType::EOF {
+			Err(ParseError::at(format!("Unexpected end of input, expected \"{:?}\".", tt), self.peek()))
 		} else {
-			panic!("Expected \"{:?}\".", tt);
+			Err(ParseError::at(format!("Expected \"{:?}\".", tt), self.peek()))
 		}
 	}
 
-	fn call(&mut self) -> Box<Expr> {
-		let func_name = self.prev().lexeme.clone();
-		self.expect(TokenType::LParen);
+	fn call(&mut self) -> Result<Box<Expr>, ParseError> {
+		let token = self.prev().clone();
+		let func_name = token.lexeme.clone();
+		self.expect(TokenType::LParen)?;
 
 		let mut args = Vec::new();
-		if self.peek().token_type != TokenType::RParen {
+		if !self.accept(TokenType::RParen) {
 			loop {
-				args.push(*self.factor());
-				if self.peek().token_type == TokenType::RParen {
-					self.advance();
-					break;
-				}
+				args.push(*self.expr()?);
 				if !self.accept(TokenType::Comma) {
+					self.expect(TokenType::RParen)?;
 					break;
 				}
 			}
-		} else {
-			self.advance();
 		}
 
-		Box::new(Expr::Call(func_name, args))
+		Ok(Box::new(Expr::Call(func_name, args, token)))
 	}
 
-	fn factor(&mut self) -> Box<Expr> {
-		if self.accept(TokenType::Number) {
-			Box::new(Expr::Literal(self.prev().value))
+	// expr := term (("+" | "-") term)*
+	fn expr(&mut self) -> Result<Box<Expr>, ParseError> {
+		let mut left = self.term()?;
+		loop {
+			let func = if self.accept(TokenType::Plus) {
+				"Add"
+			} else if self.accept(TokenType::Minus) {
+				"Sub"
+			} else {
+				return Ok(left);
+			};
+			let token = self.prev().clone();
+			let right = self.term()?;
+			left = Box::new(Expr::Call(func.to_owned(), vec![*left, *right], token));
+		}
+	}
+
+	// term := unary (("*" | "/") unary)*
+	fn term(&mut self) -> Result<Box<Expr>, ParseError> {
+		let mut left = self.unary()?;
+		loop {
+			let func = if self.accept(TokenType::Star) {
+				"Mul"
+			} else if self.accept(TokenType::Slash) {
+				"Div"
+			} else {
+				return Ok(left);
+			};
+			let token = self.prev().clone();
+			let right = self.unary()?;
+			left = Box::new(Expr::Call(func.to_owned(), vec![*left, *right], token));
+		}
+	}
+
+	// unary := "-" unary | factor
+	fn unary(&mut self) -> Result<Box<Expr>, ParseError> {
+		if !self.accept(TokenType::Minus) {
+			return self.factor();
+		}
+		let token = self.prev().clone();
+		match *self.unary()? {
+			Expr::Literal(v) => Ok(Box::new(Expr::Literal(-v))),
+			e => Ok(Box::new(Expr::Call("Sub".to_owned(), vec![Expr::Literal(0.0), e], token)))
+		}
+	}
+
+	fn factor(&mut self) -> Result<Box<Expr>, ParseError> {
+		if self.accept(TokenType::LParen) {
+			let e = self.expr()?;
+			self.expect(TokenType::RParen)?;
+			Ok(e)
+		} else if self.accept(TokenType::Number) {
+			Ok(Box::new(Expr::Literal(self.prev().value)))
+		} else if self.accept(TokenType::String) {
+			Ok(Box::new(Expr::Str(self.prev().lexeme.clone())))
 		} else if self.accept(TokenType::Identifier) {
 			if self.peek().token_type != TokenType::LParen {
-				Box::new(Expr::Identifier(self.prev().lexeme.clone()))
+				Ok(Box::new(Expr::Identifier(self.prev().lexeme.clone())))
 			} else {
 				self.call()
 			}
-		} else if self.accept(TokenType::EOF) {
-			Box::new(Expr::Literal(0.0))
+		} else if self.peek().token_type == TokenType::EOF {
+			Err(ParseError::at("Unexpected end of input.".to_owned(), self.peek()))
 		} else {
 			self.advance();
-			panic!("Syntax error: \"{:?}\"", self.prev().token_type);
+			Err(ParseError::at(format!("Syntax error: \"{:?}\"", self.prev().token_type), self.prev()))
+		}
+	}
+
+	fn annotation(&mut self) -> Result<(Token, Vec<Expr>), ParseError> {
+		let token = self.prev().clone();
+		// `@name 123` is shorthand for `@name(123)`.
+		if self.accept(TokenType::Number) {
+			return Ok((token, vec![Expr::Literal(self.prev().value)]));
+		}
+		match *self.call()? {
+			Expr::Call(_, args, _) => Ok((token, args)),
+			_ => Ok((token, Vec::new()))
 		}
 	}
 
-	fn stmt(&mut self) -> Box<Expr> {
-		let var_name = self.factor();
+	fn annotations(&mut self, target: Box<Expr>) -> Result<Box<Expr>, ParseError> {
+		let mut expr = target;
+		while self.accept(TokenType::Annotation) {
+			let (token, args) = self.annotation()?;
+			expr = Box::new(Expr::Annotate(expr, token.lexeme.clone(), args, token));
+		}
+		Ok(expr)
+	}
+
+	fn stmt(&mut self) -> Result<Box<Expr>, ParseError> {
+		if self.accept(TokenType::Annotation) {
+			let (token, args) = self.annotation()?;
+			return Ok(Box::new(Expr::Directive(token.lexeme.clone(), args, token)));
+		}
+
+		let var_name = self.expr()?;
 		if self.accept(TokenType::Equals) {
-			let val = self.factor();
-			Box::new(Expr::Assign(var_name, val))
+			let val = self.expr()?;
+			let val = self.annotations(val)?;
+			Ok(Box::new(Expr::Assign(var_name, val)))
 		} else {
-			self.advance();
-			var_name
+			self.annotations(var_name)
 		}
 	}
 
-	pub fn parse(&mut self) -> Box<Expr> {
+	pub fn parse(&mut self) -> Result<Box<Expr>, ParseError> {
 		let mut prog = Vec::new();
-		while self.prev().token_type != TokenType::EOF {
-			prog.push(*self.stmt());
+		while self.peek().token_type != TokenType::EOF {
+			prog.push(*self.stmt()?);
 		}
 		// println!("{:#?}", prog);
-		Box::new(Expr::Program(prog))
+		Ok(Box::new(Expr::Program(prog)))
 	}
 }
 
@@ -275,6 +467,8 @@ pub enum Value {
 	Number(f32),
 	NodeID(usize),
 	StoreID(usize),
+	/// Left and right channel nodes.
+	Stereo(usize, usize),
 	Nil
 }
 
@@ -284,6 +478,8 @@ impl Into<Input> for Value {
 			Value::Nil => Input::Value(0.0),
 			Value::NodeID(i) => Input::Node(i),
 			Value::StoreID(i) => Input::Store(i),
+			// Mono arguments reject pairs in `FunctionSig::check`.
+			Value::Stereo(l, _) => Input::Node(l),
 			Value::Number(v) => Input::Value(v)
 		}
 	}
@@ -298,25 +494,493 @@ impl Value {
 	}
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ArgKind {
+	Input,
+	/// A mono input or a stereo pair.
+	Channels,
+	Number,
+	Store,
+	Path,
+	Name
+}
+
+#[derive(Debug)]
+pub struct ArgSig {
+	pub name: &'static str,
+	pub kind: ArgKind
+}
+
+/// Builds the value of a call from its checked arguments. The raw argument
+/// expressions are passed too, for paths and names.
+pub type Build = fn(&mut GraphLoader, &mut NodeGraph, &[Expr], &[Value]) -> Result<Value, ParseError>;
+
+/// Argument signature of a patch function and how to build it. When `max`
+/// is `None` the last argument may be repeated.
+#[derive(Debug)]
+pub struct FunctionSig {
+	pub name: &'static str,
+	pub args: &'static [ArgSig],
+	pub min: usize,
+	pub max: Option<usize>,
+	pub build: Build
+}
+
+impl FunctionSig {
+	pub fn accepts(&self, count: usize) -> bool {
+		count >= self.min && self.max.map_or(true, |max| count <= max)
+	}
+
+	pub fn arg(&self, index: usize) -> &ArgSig {
+		&self.args[index.min(self.args.len() - 1)]
+	}
+
+	/// Checks each evaluated argument against its expected kind.
+	pub fn check(&self, args: &[Expr], values: &[Value]) -> Result<(), String> {
+		for (i, (arg, value)) in args.iter().zip(values.iter()).enumerate() {
+			let spec = self.arg(i);
+			match (spec.kind, arg, value) {
+				(ArgKind::Path, Expr::Str(_), _) |
+				(ArgKind::Name, Expr::Str(_), _) |
+				(ArgKind::Number, _, Value::Number(_)) |
+				(ArgKind::Store, _, Value::StoreID(_)) |
+				(ArgKind::Channels, ..) => {},
+				(ArgKind::Input, _, Value::Stereo(..)) => {
+					return Err(format!("{}: \"{}\" must be a mono signal.", self.name, spec.name))
+				},
+				(ArgKind::Input, ..) => {},
+				(ArgKind::Path, ..) => return Err(format!("{}: \"{}\" must be a file path.", self.name, spec.name)),
+				(ArgKind::Name, ..) => return Err(format!("{}: \"{}\" must be a quoted name.", self.name, spec.name)),
+				(ArgKind::Number, ..) => return Err(format!("{}: \"{}\" must be a number.", self.name, spec.name)),
+				(ArgKind::Store, ..) => return Err(format!("{}: \"{}\" must be a store.", self.name, spec.name))
+			}
+		}
+		Ok(())
+	}
+
+	pub fn arity(&self) -> String {
+		match self.max {
+			Some(max) if max == self.min => format!("{}", max),
+			Some(max) => format!("{} to {}", self.min, max),
+			None => format!("at least {}", self.min)
+		}
+	}
+}
+
+const fn arg(name: &'static str, kind: ArgKind) -> ArgSig {
+	ArgSig { name, kind }
+}
+
+const fn sig(name: &'static str, args: &'static [ArgSig], build: Build) -> FunctionSig {
+	FunctionSig { name, args, min: args.len(), max: Some(args.len()), build }
+}
+
+const fn opt(name: &'static str, args: &'static [ArgSig], min: usize, build: Build) -> FunctionSig {
+	FunctionSig { name, args, min, max: Some(args.len()), build }
+}
+
+fn node(id: usize) -> Result<Value, ParseError> {
+	Ok(Value::NodeID(id))
+}
+
+// Arithmetic on two constants is folded instead of becoming a node.
+fn arith(graph: &mut NodeGraph, a: &[Value], fold: fn(f32, f32) -> f32, create: fn(&mut NodeGraph, Input, Input) -> usize) -> Result<Value, ParseError> {
+	match (a[0], a[1]) {
+		(Value::Number(x), Value::Number(y)) => Ok(Value::Number(fold(x, y))),
+		(x, y) => node(create(graph, x.into(), y.into()))
+	}
+}
+
+fn path_arg(args: &[Expr], index: usize) -> &str {
+	match &args[index] {
+		Expr::Str(path) => path,
+		_ => unreachable!()
+	}
+}
+
+fn load_path(args: &[Expr], index: usize) -> Result<Vec<f32>, String> {
+	wav::load_mono(path_arg(args, index))
+}
+
+const MAP_ARGS: &[ArgSig] = &[
+	arg("input", ArgKind::Input),
+	arg("from_min", ArgKind::Number),
+	arg("from_max", ArgKind::Number),
+	arg("to_min", ArgKind::Number),
+	arg("to_max", ArgKind::Number)
+];
+const LFO_ARGS: &[ArgSig] = &[
+	arg("freq", ArgKind::Input),
+	arg("depth", ArgKind::Input),
+	arg("offset", ArgKind::Input)
+];
+const NOISE_ARGS: &[ArgSig] = &[arg("amp", ArgKind::Input), arg("seed", ArgKind::Number)];
+const VOICE_ARGS: &[ArgSig] = &[arg("voice", ArgKind::Number)];
+const OSC_ARGS: &[ArgSig] = &[arg("freq", ArgKind::Input), arg("amp", ArgKind::Input)];
+const FILTER_ARGS: &[ArgSig] = &[
+	arg("input", ArgKind::Input),
+	arg("cutoff", ArgKind::Input),
+	arg("q", ArgKind::Input)
+];
+const BINARY_ARGS: &[ArgSig] = &[arg("a", ArgKind::Input), arg("b", ArgKind::Input)];
+
+static SIGNATURES: &[FunctionSig] = &[
+	opt("CreateStore", &[arg("initial", ArgKind::Number), arg("smooth_ms", ArgKind::Number)], 0, |l, g, _, a| {
+		let initial = a.first().map_or(0.0, |v| v.get_number());
+		let smooth = a.get(1).map_or(0.0, |v| v.get_number());
+		match l.store_name.take() {
+			Some(name) => {
+				let id = g.create_named_store(&name);
+				g.configure_store(id, initial, smooth);
+				Ok(Value::StoreID(id))
+			},
+			None => Ok(Value::StoreID(g.create_smoothed_store(initial, smooth)))
+		}
+	}),
+	sig("Reader", &[arg("name", ArgKind::Name)], |_, g, args, _| match &args[0] {
+		Expr::Str(name) => Ok(Value::StoreID(g.create_named_store(name))),
+		_ => unreachable!()
+	}),
+	sig("Tempo", &[arg("bpm", ArgKind::Number)], |_, g, _, a| {
+		g.set_tempo(a[0].get_number());
+		Ok(Value::Nil)
+	}),
+	opt("LFO", LFO_ARGS, 1, |_, g, _, a| {
+		let depth = a.get(1).map_or(Input::Value(1.0), |&v| v.into());
+		let offset = a.get(2).map_or(Input::Value(0.0), |&v| v.into());
+		node(g.create_lfo(a[0].into(), depth, offset))
+	}),
+	opt("LFOBi", LFO_ARGS, 1, |_, g, _, a| {
+		let depth = a.get(1).map_or(Input::Value(1.0), |&v| v.into());
+		let offset = a.get(2).map_or(Input::Value(0.0), |&v| v.into());
+		node(g.create_lfo_bi(a[0].into(), depth, offset))
+	}),
+	sig("PulseLFO", &[arg("rate", ArgKind::Input), arg("width", ArgKind::Input)], |_, g, _, a| {
+		node(g.create_pulse_lfo(a[0].into(), a[1].into()))
+	}),
+	sig("SyncLFO", &[arg("division", ArgKind::Input)], |_, g, _, a| node(g.create_sync_lfo(a[0].into()))),
+	sig("Clock", &[arg("division", ArgKind::Input)], |_, g, _, a| node(g.create_clock(a[0].into()))),
+	sig("SampleHold", &[arg("signal", ArgKind::Input), arg("trigger", ArgKind::Input)], |_, g, _, a| {
+		node(g.create_sample_hold(a[0].into(), a[1].into()))
+	}),
+	opt("MidiFreq", VOICE_ARGS, 0, |_, g, _, a| {
+		node(g.create_midi_freq(a.first().map_or(0.0, |v| v.get_number()).max(0.0) as usize))
+	}),
+	opt("MidiGate", VOICE_ARGS, 0, |_, g, _, a| {
+		node(g.create_midi_gate(a.first().map_or(0.0, |v| v.get_number()).max(0.0) as usize))
+	}),
+	sig("Poly", &[arg("voices", ArgKind::Number), arg("voice", ArgKind::Input)], |l, g, _, a| {
+		let count = a[0].get_number();
+		if count < 1.0 {
+			return l.fail(format!("Poly needs at least 1 voice, got {}.", count));
+		}
+		let template = match a[1] {
+			Value::NodeID(id) => id,
+			_ => return l.fail("Poly needs a node to use as its voice.".to_owned())
+		};
+		let voices = match g.spawn_voices(template, count as usize - 1) {
+			Ok(voices) => voices,
+			Err(e) => return l.fail(format!("{}.", e))
+		};
+		let mut sum = template;
+		for voice in voices {
+			sum = g.create_add(Input::Node(sum), Input::Node(voice));
+		}
+		node(sum)
+	}),
+	sig("Output", &[arg("from", ArgKind::Input)], |l, g, _, a| match g.create_output(a[0].into()) {
+		Ok(id) => node(id),
+		Err(e) => l.fail(e.to_string())
+	}),
+	opt("StereoOutput", &[arg("left", ArgKind::Channels), arg("right", ArgKind::Input)], 1, |l, g, _, a| {
+		let (left, right) = match (a[0], a.get(1).cloned()) {
+			(Value::Stereo(l, r), None) => (Input::Node(l), Input::Node(r)),
+			(Value::Stereo(..), Some(_)) => {
+				return l.fail("StereoOutput takes either a stereo pair or a left and right signal.".to_owned())
+			},
+			(mono, None) => (mono.into(), mono.into()),
+			(l, Some(r)) => (l.into(), r.into())
+		};
+		match g.create_stereo_output(left, right) {
+			Ok(id) => node(id),
+			Err(e) => l.fail(e.to_string())
+		}
+	}),
+	sig("Pan", &[arg("input", ArgKind::Input), arg("position", ArgKind::Input)], |_, g, _, a| {
+		let (l, r) = g.create_pan(a[0].into(), a[1].into());
+		Ok(Value::Stereo(l, r))
+	}),
+	sig("AutoPan", &[arg("input", ArgKind::Input), arg("rate", ArgKind::Input), arg("depth", ArgKind::Input)], |_, g, _, a| {
+		let (l, r) = g.create_auto_pan(a[0].into(), a[1].into(), a[2].into());
+		Ok(Value::Stereo(l, r))
+	}),
+	sig("Sine", OSC_ARGS, |_, g, _, a| node(g.create_sine(a[0].into(), a[1].into()))),
+	sig("Square", OSC_ARGS, |_, g, _, a| node(g.create_square(a[0].into(), a[1].into()))),
+	sig("Saw", OSC_ARGS, |_, g, _, a| node(g.create_saw(a[0].into(), a[1].into()))),
+	sig("Triangle", OSC_ARGS, |_, g, _, a| node(g.create_triangle(a[0].into(), a[1].into()))),
+	sig("SawBL", OSC_ARGS, |_, g, _, a| node(g.create_saw_bl(a[0].into(), a[1].into()))),
+	sig("SquareBL", OSC_ARGS, |_, g, _, a| node(g.create_square_bl(a[0].into(), a[1].into()))),
+	sig("Wave", &[arg("path", ArgKind::Path), arg("freq", ArgKind::Input), arg("amp", ArgKind::Input)], |l, g, args, a| {
+		let table = match load_path(args, 0) {
+			Ok(table) => table,
+			Err(e) => return l.fail(format!("Failed to load wavetable. {}", e))
+		};
+		if table.is_empty() {
+			return l.fail("Wavetable is empty.".to_owned());
+		}
+		let id = g.load_wavetable(table);
+		match g.create_wavetable(a[1].into(), a[2].into(), id) {
+			Ok(id) => {
+				g.set_path(id, path_arg(args, 0));
+				node(id)
+			},
+			Err(e) => l.fail(format!("{}.", e))
+		}
+	}),
+	opt("Noise", NOISE_ARGS, 0, |_, g, _, a| {
+		let amp = a.first().map_or(Input::Value(1.0), |&v| v.into());
+		node(g.create_noise(NoiseKind::White, amp, a.get(1).map(|v| v.get_number() as u32)))
+	}),
+	opt("PinkNoise", NOISE_ARGS, 0, |_, g, _, a| {
+		let amp = a.first().map_or(Input::Value(1.0), |&v| v.into());
+		node(g.create_pink_noise(amp, a.get(1).map(|v| v.get_number() as u32)))
+	}),
+	sig("Osc", &[arg("freq", ArgKind::Input), arg("amp", ArgKind::Input), arg("shape", ArgKind::Input)], |_, g, _, a| {
+		node(g.create_osc(a[0].into(), a[1].into(), a[2].into()))
+	}),
+	FunctionSig {
+		name: "Additive",
+		args: &[arg("fundamental", ArgKind::Input), arg("partial", ArgKind::Number)],
+		min: 1,
+		max: None,
+		build: |_, g, _, a| {
+			let amps = a[1..].iter().map(|v| v.get_number()).collect();
+			node(g.create_additive(a[0].into(), amps))
+		}
+	},
+	sig("Map", MAP_ARGS, |_, g, _, a| {
+		let (b, c, d, e) = (a[1].get_number(), a[2].get_number(), a[3].get_number(), a[4].get_number());
+		node(g.create_map(a[0].into(), b, c, d, e, false))
+	}),
+	sig("MapClamped", MAP_ARGS, |_, g, _, a| {
+		let (b, c, d, e) = (a[1].get_number(), a[2].get_number(), a[3].get_number(), a[4].get_number());
+		node(g.create_map(a[0].into(), b, c, d, e, true))
+	}),
+	sig("Unipolar", &[arg("input", ArgKind::Input)], |_, g, _, a| node(g.create_unipolar(a[0].into()))),
+	sig("Bipolar", &[arg("input", ArgKind::Input)], |_, g, _, a| node(g.create_bipolar(a[0].into()))),
+	sig("Add", BINARY_ARGS, |_, g, _, a| arith(g, a, |x, y| x + y, NodeGraph::create_add)),
+	sig("Sub", BINARY_ARGS, |_, g, _, a| arith(g, a, |x, y| x - y, NodeGraph::create_sub)),
+	sig("Mul", BINARY_ARGS, |_, g, _, a| arith(g, a, |x, y| x * y, NodeGraph::create_mul)),
+	sig("Div", BINARY_ARGS, |_, g, _, a| {
+		arith(g, a, |x, y| if y != 0.0 { x / y } else { 0.0 }, NodeGraph::create_div)
+	}),
+	sig("Writer", &[arg("store", ArgKind::Store), arg("value", ArgKind::Input)], |l, g, _, a| {
+		let id = match a[0] {
+			Value::StoreID(id) => id,
+			_ => unreachable!()
+		};
+		match g.create_writer(id, a[1].into()) {
+			Ok(id) => node(id),
+			Err(e) => l.fail(format!("{}.", e))
+		}
+	}),
+	sig("Clip", &[arg("input", ArgKind::Input), arg("threshold", ArgKind::Input)], |_, g, _, a| {
+		node(g.create_clip(a[0].into(), a[1].into()))
+	}),
+	sig("Mix", &[arg("a", ArgKind::Input), arg("b", ArgKind::Input), arg("factor", ArgKind::Number)], |_, g, _, a| {
+		node(g.create_mix(a[0].into(), a[1].into(), a[2].get_number()))
+	}),
+	sig("AM", &[
+		arg("carrier", ArgKind::Input),
+		arg("modulator", ArgKind::Input),
+		arg("depth", ArgKind::Input),
+		arg("mode", ArgKind::Number)
+	], |l, g, _, a| {
+		let mode = match a[3].get_number() {
+			m if m == 0.0 || m == 1.0 => m as u8,
+			m => return l.fail(format!("Invalid AM mode {}, expected 0 (ring) or 1 (AM).", m))
+		};
+		node(g.create_am(a[0].into(), a[1].into(), a[2].into(), mode))
+	}),
+	sig("LowPass", FILTER_ARGS, |_, g, _, a| {
+		node(g.create_filter(a[0].into(), a[1].into(), a[2].into(), FilterMode::LowPass))
+	}),
+	sig("HighPass", FILTER_ARGS, |_, g, _, a| {
+		node(g.create_filter(a[0].into(), a[1].into(), a[2].into(), FilterMode::HighPass))
+	}),
+	sig("PeriodSamples", &[arg("freq", ArgKind::Input)], |_, g, _, a| match a[0] {
+		Value::Number(freq) => Ok(Value::Number(g.period_samples(freq))),
+		freq => node(g.create_period_samples(freq.into()))
+	}),
+	sig("AutoGain", &[
+		arg("source", ArgKind::Input),
+		arg("target_rms", ArgKind::Number),
+		arg("time_ms", ArgKind::Number)
+	], |_, g, _, a| node(g.create_auto_gain(a[0].into(), a[1].get_number(), a[2].get_number()))),
+	sig("Delay", &[
+		arg("input", ArgKind::Channels),
+		arg("time", ArgKind::Input),
+		arg("feedback", ArgKind::Input),
+		arg("mix", ArgKind::Input)
+	], |_, g, _, a| match a[0] {
+		Value::Stereo(l, r) => {
+			let (l, r) = g.create_stereo_delay(Input::Node(l), Input::Node(r), a[1].into(), a[2].into(), a[3].into());
+			Ok(Value::Stereo(l, r))
+		},
+		input => node(g.create_delay(input.into(), a[1].into(), a[2].into(), a[3].into()))
+	}),
+	sig("AutoWah", &[
+		arg("source", ArgKind::Input),
+		arg("sensitivity", ArgKind::Input),
+		arg("base_cutoff", ArgKind::Input)
+	], |_, g, _, a| node(g.create_auto_wah(a[0].into(), a[1].into(), a[2].into()))),
+	sig("Tilt", &[arg("source", ArgKind::Input), arg("amount", ArgKind::Input)], |_, g, _, a| {
+		node(g.create_tilt(a[0].into(), a[1].into()))
+	}),
+	sig("PitchFollow", &[arg("source", ArgKind::Input)], |_, g, _, a| node(g.create_pitch_follow(a[0].into()))),
+	sig("Freeze", &[arg("source", ArgKind::Input), arg("hold", ArgKind::Input)], |_, g, _, a| {
+		node(g.create_freeze(a[0].into(), a[1].into()))
+	}),
+	sig("Convolve", &[arg("source", ArgKind::Channels), arg("ir", ArgKind::Path)], |l, g, args, a| {
+		let ir = match load_path(args, 1) {
+			Ok(ir) => ir,
+			Err(e) => return l.fail(format!("Failed to load impulse response. {}", e))
+		};
+		match a[0] {
+			Value::Stereo(l, r) => {
+				let (l, r) = g.create_stereo_convolve(Input::Node(l), Input::Node(r), &ir);
+				g.set_path(l, path_arg(args, 1));
+				g.set_path(r, path_arg(args, 1));
+				Ok(Value::Stereo(l, r))
+			},
+			source => {
+				let id = g.create_convolve(source.into(), &ir);
+				g.set_path(id, path_arg(args, 1));
+				node(id)
+			}
+		}
+	})
+];
+
+/// Every function a patch can call, with its expected arguments.
+pub fn function_signatures() -> &'static [FunctionSig] {
+	SIGNATURES
+}
+
+/// Names of every function a patch can call.
+pub fn builtin_names() -> Vec<&'static str> {
+	function_signatures().iter().map(|sig| sig.name).collect()
+}
+
+// Literal argument of a top-level directive, read before the graph exists.
+fn header(prog: &Expr, name: &str) -> Option<f32> {
+	let exprs = match prog {
+		Expr::Program(exprs) => exprs,
+		_ => return None
+	};
+	exprs.iter().filter_map(|e| match e {
+		Expr::Directive(n, args, _) if n == name => match args.first() {
+			Some(Expr::Literal(v)) if *v > 0.0 => Some(*v),
+			_ => None
+		},
+		_ => None
+	}).next()
+}
+
 pub struct GraphLoader {
 	variables: HashMap<String, Value>,
-	parser: Parser
+	source: String,
+	strict: bool,
+	lenient: bool,
+	errors: Vec<String>,
+	max_nodes: Option<usize>,
+	sample_rate: Option<u32>,
+	buffer_size: Option<usize>,
+	functions: HashMap<&'static str, &'static FunctionSig>,
+	// Variable a CreateStore call is being assigned to.
+	store_name: Option<String>,
+	// Call being built, so errors can point at it.
+	token: Option<Token>
 }
 
 impl GraphLoader {
-	pub fn new(file: &str) -> GraphLoader {
-		let s = match fs::read_to_string(file) {
-			Ok(s) => s,
-			Err(e) => panic!("Error: {}", e)
-		};
+	pub fn new(file: &str) -> Result<GraphLoader, ParseError> {
+		match fs::read_to_string(file) {
+			Ok(s) => Ok(GraphLoader::from_source(s.as_str())),
+			Err(e) => Err(ParseError::new(format!("{}: {}", file, e)))
+		}
+	}
+
+	pub fn from_source(source: &str) -> GraphLoader {
 		GraphLoader {
-			parser: Parser::new(s.as_str()),
-			variables: HashMap::new()
+			source: source.to_owned(),
+			variables: HashMap::new(),
+			strict: false,
+			lenient: false,
+			errors: Vec::new(),
+			max_nodes: None,
+			sample_rate: None,
+			buffer_size: None,
+			functions: function_signatures().iter().map(|sig| (sig.name, sig)).collect(),
+			store_name: None,
+			token: None
 		}
 	}
 
-	fn visit(&mut self, expr: Expr, graph: &mut NodeGraph) -> Value {
-		match expr {
+	/// In strict mode, load-time warnings become errors.
+	pub fn set_strict(&mut self, strict: bool) {
+		self.strict = strict;
+	}
+
+	/// Id of the store assigned to the variable `name`, if any.
+	pub fn store_id(&self, name: &str) -> Option<usize> {
+		match self.variables.get(name) {
+			Some(Value::StoreID(id)) => Some(*id),
+			_ => None
+		}
+	}
+
+	/// Fails the load as soon as the patch creates more than `limit` nodes.
+	pub fn set_max_nodes(&mut self, limit: usize) {
+		self.max_nodes = Some(limit);
+	}
+
+	/// Builds the graph at `rate` Hz, overriding the patch's `@samplerate`.
+	pub fn set_sample_rate(&mut self, rate: u32) {
+		self.sample_rate = Some(rate);
+	}
+
+	/// Overrides the patch's `@buffer` size, in frames.
+	pub fn set_buffer_size(&mut self, frames: usize) {
+		self.buffer_size = Some(frames);
+	}
+
+	fn error(&self, msg: String) -> ParseError {
+		match &self.token {
+			Some(token) => ParseError::at(msg, token),
+			None => ParseError::new(msg)
+		}
+	}
+
+	fn fail(&mut self, msg: String) -> Result<Value, ParseError> {
+		let e = self.error(msg);
+		if !self.lenient {
+			return Err(e);
+		}
+		self.errors.push(e.to_string());
+		Ok(Value::Nil)
+	}
+
+	fn warn(&self, msg: String, token: &Token) -> Result<(), ParseError> {
+		if self.strict {
+			return Err(ParseError::at(msg, token));
+		}
+		println!("Warning: {}", msg);
+		Ok(())
+	}
+
+	fn visit(&mut self, expr: Expr, graph: &mut NodeGraph) -> Result<Value, ParseError> {
+		let value = match expr {
 			Expr::Literal(v) => Value::Number(v),
 			Expr::Identifier(s) => {
 				if !self.variables.contains_key(&s) {
@@ -329,9 +993,17 @@ impl GraphLoader {
 			Expr::Assign(a, b) => {
 				let _a = match *a {
 					Expr::Identifier(nam) => nam.clone(),
-					_ => panic!("Invalid variable.")
+					_ => return Err(ParseError::new("Invalid variable.".to_owned()))
 				};
-				let _b = self.visit(*b, graph);
+				if let Expr::Call(func, ..) = &*b {
+					if func == "CreateStore" {
+						self.store_name = Some(_a.clone());
+					}
+				}
+				let _b = self.visit(*b, graph)?;
+				if let Value::StoreID(id) = _b {
+					graph.name_store(&_a, id);
+				}
 				let val = match self.variables.entry(_a) {
 					Occupied(v) => v.into_mut(),
 					Vacant(v) => v.insert(Value::Nil)
@@ -340,93 +1012,594 @@ impl GraphLoader {
 
 				Value::Nil
 			},
-			Expr::Call(func, args) => {
-				match func.as_str() {
-					"CreateStore" => {
-						Value::StoreID(graph.create_value_store())
-					},
-					"LFO" => {
-						let freq = self.visit(args[0].clone(), graph).into();
-						Value::NodeID(graph.create_lfo(freq))
-					},
-					"Output" => {
-						let from = self.visit(args[0].clone(), graph).into();
-						Value::NodeID(graph.create_output(from))
-					},
-					"Sine" => {
-						let freq = self.visit(args[0].clone(), graph).into();
-						let amp  = self.visit(args[1].clone(), graph).into();
-						Value::NodeID(graph.create_sine(freq, amp))
-					},
-					"Square" => {
-						let freq = self.visit(args[0].clone(), graph).into();
-						let amp  = self.visit(args[1].clone(), graph).into();
-						Value::NodeID(graph.create_square(freq, amp))
-					},
-					"Saw" => {
-						let freq = self.visit(args[0].clone(), graph).into();
-						let amp  = self.visit(args[1].clone(), graph).into();
-						Value::NodeID(graph.create_saw(freq, amp))
-					},
-					"Triangle" => {
-						let freq = self.visit(args[0].clone(), graph).into();
-						let amp  = self.visit(args[1].clone(), graph).into();
-						Value::NodeID(graph.create_triangle(freq, amp))
-					},
-					"Map" => {
-						let input = self.visit(args[0].clone(), graph).into();
-						let a  = self.visit(args[1].clone(), graph).get_number();
-						let b  = self.visit(args[2].clone(), graph).get_number();
-						let c  = self.visit(args[3].clone(), graph).get_number();
-						let d  = self.visit(args[4].clone(), graph).get_number();
-						Value::NodeID(graph.create_map(input, a, b, c, d))
+			Expr::Call(func, args, token) => {
+				self.token = Some(token.clone());
+				let store_name = if func == "CreateStore" { self.store_name.take() } else { None };
+				let sig = match self.functions.get(func.as_str()) {
+					Some(&sig) => sig,
+					None => return self.fail(format!("Invalid function: \"{}\"", func))
+				};
+				if !sig.accepts(args.len()) {
+					return self.fail(format!("{} expects {} arguments, got {}.", func, sig.arity(), args.len()));
+				}
+				let mut a = Vec::with_capacity(args.len());
+				for arg in args.iter() {
+					a.push(self.visit(arg.clone(), graph)?);
+				}
+				self.token = Some(token);
+				if let Err(e) = sig.check(&args, &a) {
+					return self.fail(e);
+				}
+
+				self.store_name = store_name;
+				let value = (sig.build)(self, graph, &args, &a)?;
+				if let Err(e) = graph.check_node_limit() {
+					return Err(self.error(e));
+				}
+				value
+			},
+			Expr::Annotate(target, name, args, token) => {
+				let value = self.visit(*target, graph)?;
+				match name.as_str() {
+					"after" => {
+						let node = match value {
+							Value::NodeID(id) => id,
+							_ => return Err(ParseError::at("\"@after\" can only annotate a node.".to_owned(), &token))
+						};
+						for arg in args.into_iter() {
+							let before = match self.visit(arg, graph)? {
+								Value::NodeID(id) => id,
+								_ => return Err(ParseError::at("\"@after\" expects a node.".to_owned(), &token))
+							};
+							if let Err(e) = graph.force_order(before, node) {
+								return Err(ParseError::at(format!("{}.", e), &token));
+							}
+						}
 					},
-					"Add" => {
-						let a = self.visit(args[0].clone(), graph).into();
-						let b = self.visit(args[1].clone(), graph).into();
-						Value::NodeID(graph.create_add(a, b))
+					_ => return Err(ParseError::at(format!("Invalid annotation: \"@{}\"", name), &token))
+				}
+				value
+			},
+			Expr::Directive(name, args, token) => {
+				match name.as_str() {
+					"samplerate" => {
+						let rate = match args.first() {
+							Some(arg) => self.visit(arg.clone(), graph)?.get_number() as u32,
+							None => return Err(ParseError::at("\"@samplerate\" expects a sample rate.".to_owned(), &token))
+						};
+						if rate != graph.sample_rate() {
+							self.warn(format!(
+								"Patch expects a sample rate of {} Hz, but the engine runs at {} Hz.",
+								rate, graph.sample_rate()
+							), &token)?;
+						}
 					},
-					"Sub" => {
-						let a = self.visit(args[0].clone(), graph).into();
-						let b = self.visit(args[1].clone(), graph).into();
-						Value::NodeID(graph.create_sub(a, b))
+					"buffer" => {
+						let frames = match args.first() {
+							Some(arg) => self.visit(arg.clone(), graph)?.get_number() as usize,
+							None => return Err(ParseError::at("\"@buffer\" expects a buffer size.".to_owned(), &token))
+						};
+						if frames != graph.buffer_size() {
+							self.warn(format!(
+								"Patch asks for {} frame buffers, but the engine uses {}.",
+								frames, graph.buffer_size()
+							), &token)?;
+						}
 					},
-					"Mul" => {
-						let a = self.visit(args[0].clone(), graph).into();
-						let b = self.visit(args[1].clone(), graph).into();
-						Value::NodeID(graph.create_mul(a, b))
+					"declick" => {
+						let samples = match args.first() {
+							Some(arg) => self.visit(arg.clone(), graph)?.get_number(),
+							None => return Err(ParseError::at("\"@declick\" expects a sample count.".to_owned(), &token))
+						};
+						graph.set_declick(samples.max(0.0) as u32);
 					},
-					"Writer" => {
-						let a = match self.visit(args[0].clone(), graph).into() {
-							Value::StoreID(id) => id,
-							_ => panic!("Invalid Store ID.")
+					"interp" => {
+						let interp = match args.first() {
+							Some(Expr::Str(mode)) if mode == "linear" => DelayInterp::Linear,
+							Some(Expr::Str(mode)) if mode == "cubic" => DelayInterp::Cubic,
+							_ => return Err(ParseError::at("\"@interp\" expects \"linear\" or \"cubic\".".to_owned(), &token))
 						};
-						let input = self.visit(args[1].clone(), graph).into();
-						Value::NodeID(graph.create_writer(a, input))
+						graph.set_delay_interp(interp);
 					},
-					"Mix" => {
-						let a = self.visit(args[0].clone(), graph).into();
-						let b = self.visit(args[1].clone(), graph).into();
-						let fac = self.visit(args[2].clone(), graph).get_number();
-						Value::NodeID(graph.create_mix(a, b, fac))
+					"seed" => {
+						let seed = match args.first() {
+							Some(arg) => self.visit(arg.clone(), graph)?.get_number(),
+							None => return Err(ParseError::at("\"@seed\" expects a number.".to_owned(), &token))
+						};
+						graph.set_seed(seed.max(0.0) as u32);
 					},
-					_ => panic!("Invalid function: \"{}\"", func)
+					_ => return Err(ParseError::at(format!("Invalid directive: \"@{}\"", name), &token))
 				}
+				Value::Nil
 			},
 			Expr::Program(exprs) => {
 				for expr in exprs.into_iter() {
-					self.visit(expr, graph);
+					self.visit(expr, graph)?;
 				}
 				Value::Nil
 			},
 			_ => Value::Nil
+		};
+		Ok(value)
+	}
+
+	/// Loads the patch, replacing nodes that fail to build with silence
+	/// instead of aborting. Returns the errors that were skipped over.
+	/// Syntax errors still fail the whole load.
+	pub fn load_lenient(&mut self) -> Result<(NodeGraph, Vec<String>), ParseError> {
+		self.lenient = true;
+		let graph = self.load();
+		self.lenient = false;
+		let errors = self.errors.drain(..).collect();
+		Ok((graph?, errors))
+	}
+
+	pub fn load(&mut self) -> Result<NodeGraph, ParseError> {
+		let prog = Parser::new(&self.source)?.parse()?;
+		let rate = self.sample_rate.or_else(|| header(&prog, "samplerate").map(|v| v as u32));
+		let mut graph = NodeGraph::new(rate.unwrap_or(44100));
+		if let Some(frames) = self.buffer_size.or_else(|| header(&prog, "buffer").map(|v| v as usize)) {
+			graph.set_buffer_size(frames);
+		}
+		if let Some(limit) = self.max_nodes {
+			graph.set_max_nodes(limit);
+		}
+		self.visit(*prog, &mut graph)?;
+		graph.update_order().map_err(|e| ParseError::new(format!("{}.", e)))?;
+		Ok(graph)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::f32::consts::PI;
+
+	fn render(source: &str, samples: usize) -> Vec<f32> {
+		let mut graph = GraphLoader::from_source(source).load().unwrap();
+		(0..samples).map(|_| graph.sample()).collect()
+	}
+
+	/// Magnitude of the `freq` Hz component, by Goertzel.
+	fn magnitude(samples: &[f32], freq: f32, sample_rate: f32) -> f32 {
+		let coeff = 2.0 * (2.0 * PI * freq / sample_rate).cos();
+		let (mut s1, mut s2) = (0.0, 0.0);
+		for &x in samples {
+			let s = x + coeff * s1 - s2;
+			s2 = s1;
+			s1 = s;
+		}
+		(s1 * s1 + s2 * s2 - coeff * s1 * s2).max(0.0).sqrt()
+	}
+
+	#[test]
+	fn sine_renders_end_to_end() {
+		let out = render("Output(Sine(440, 0.5))", 44100);
+		let rms = (out.iter().map(|s| s * s).sum::<f32>() / out.len() as f32).sqrt();
+		assert!((rms - 0.5 / 2f32.sqrt()).abs() < 0.01, "rms {}", rms);
+
+		let peak = out.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+		assert!((peak - 0.5).abs() < 0.01, "peak {}", peak);
+
+		let window = &out[..4410];
+		let dominant = (10..200)
+			.map(|bin| bin as f32 * 10.0)
+			.max_by(|a, b| magnitude(window, *a, 44100.0).partial_cmp(&magnitude(window, *b, 44100.0)).unwrap())
+			.unwrap();
+		assert_eq!(dominant, 440.0);
+	}
+
+	#[test]
+	fn constant_output_is_silent() {
+		assert!(render("Output(0.0)", 4410).iter().all(|&s| s == 0.0));
+	}
+
+	#[test]
+	fn to_source_keeps_wave_and_convolve_paths() {
+		let path = env::temp_dir().join(format!("twen-source-{}.wav", std::process::id()));
+		let mut table = GraphLoader::from_source("Output(Saw(441, 0.5))").load().unwrap();
+		wav::render_to_wav(&mut table, 0.01, 44100, &path, None).unwrap();
+		let path = path.to_str().unwrap().to_owned();
+
+		let source = format!(
+			"L = Wave(\"{0}\", 220, 0.5)\nStereoOutput(Convolve(Pan(L, 0.3), \"{0}\"))",
+			path
+		);
+		let mut graph = GraphLoader::from_source(&source).load().unwrap();
+		let written = graph.to_source();
+		assert_eq!(written.matches(&format!("\"{}\"", path)).count(), 2, "{}", written);
+
+		let mut copy = GraphLoader::from_source(&written).load().unwrap();
+		fs::remove_file(&path).unwrap();
+		for _ in 0..4096 {
+			assert_eq!(graph.sample_stereo(), copy.sample_stereo());
 		}
 	}
 
-	pub fn load(&mut self) -> NodeGraph {
-		let prog = self.parser.parse();
-		let mut graph = NodeGraph::new(44100);
-		self.visit(*prog, &mut graph);
-		graph
+	fn load_error(source: &str) -> ParseError {
+		match GraphLoader::from_source(source).load() {
+			Ok(_) => panic!("{:?} loaded", source),
+			Err(e) => e
+		}
+	}
+
+	#[test]
+	fn loader_errors_point_at_the_call() {
+		let token = load_error("A = Sine(440, 1)\nOutput(Mix(A, Nope(1), 0.5))").token.unwrap();
+		assert_eq!((token.line, token.col, token.lexeme.as_str()), (2, 15, "Nope"));
+
+		let token = load_error("Output(Sine(440, 1, 2, 3))").token.unwrap();
+		assert_eq!((token.line, token.col), (1, 8));
+
+		let mut loader = GraphLoader::from_source("Output(\n  Sine(1, 2, 3, 4)\n)");
+		let (_, errors) = loader.load_lenient().unwrap();
+		assert!(errors[0].starts_with("2:3: "), "{:?}", errors);
+	}
+
+	#[test]
+	fn mismatched_sample_rate_is_flagged() {
+		let source = "@samplerate(44100)\nOutput(0.0)";
+		let mut loader = GraphLoader::from_source(source);
+		loader.set_sample_rate(48000);
+		assert!(loader.load().is_ok());
+
+		loader.set_strict(true);
+		let e = match loader.load() {
+			Ok(_) => panic!("strict load accepted a 44100 Hz patch at 48000 Hz"),
+			Err(e) => e
+		};
+		assert_eq!(e.message, "Patch expects a sample rate of 44100 Hz, but the engine runs at 48000 Hz.");
+		assert_eq!(e.token.map(|t| t.line), Some(1));
+
+		let mut loader = GraphLoader::from_source(source);
+		loader.set_strict(true);
+		assert!(loader.load().is_ok());
+	}
+
+	#[test]
+	fn period_samples_converts_at_the_engine_rate() {
+		// Scaled down to stay under the master clip.
+		assert_eq!(render("Output(Div(PeriodSamples(441), 1000))", 1), vec![0.1]);
+		let out = render("F = CreateStore(441)\nOutput(Div(PeriodSamples(F), 1000))", 2);
+		assert_eq!(out, vec![0.1, 0.1]);
+		let out = render("F = CreateStore(-5)\nOutput(PeriodSamples(F))", 2);
+		assert_eq!(out, vec![0.0, 0.0]);
+	}
+
+	#[test]
+	fn smoothed_store_writes_ramp() {
+		let out = render("S = CreateStore(0, 10)\nWriter(S, 1)\nOutput(S)", 4410);
+		assert!(out[0] > 0.0 && out[0] < 0.01, "first read jumped to {}", out[0]);
+		assert!(out.windows(2).all(|w| w[1] > w[0] && w[1] - w[0] < 0.01));
+		// One time constant in, about 63% of the way there.
+		assert!((out[440] - 0.632).abs() < 0.01, "{}", out[440]);
+		assert!(out[4409] > 0.999);
+
+		let out = render("S = CreateStore(0)\nWriter(S, 1)\nOutput(S)", 2);
+		assert_eq!(out, vec![1.0, 1.0]);
+	}
+
+	#[test]
+	fn auto_gain_levels_a_jump_back_to_target() {
+		let mut loader = GraphLoader::from_source("A = CreateStore(0.2)\nOutput(AutoGain(Sine(441, A), 0.25, 50))");
+		let mut graph = loader.load().unwrap();
+		graph.schedule_store(44100, loader.store_id("A").unwrap(), 0.4);
+		let out: Vec<f32> = (0..88200).map(|_| graph.sample()).collect();
+		let rms = |from: usize| (out[from..from + 4410].iter().map(|s| s * s).sum::<f32>() / 4410.0).sqrt();
+
+		assert!((rms(39690) - 0.25).abs() < 0.01, "settled at {}", rms(39690));
+		assert!(rms(44100) > 0.3, "jump was hidden: {}", rms(44100));
+		assert!((rms(83790) - 0.25).abs() < 0.01, "resettled at {}", rms(83790));
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn pulse_lfo_gate_follows_its_width() {
+		let out = render("Output(PulseLFO(4, 0.25))", 44100);
+		assert!(out.iter().all(|&s| s == 0.0 || s == 1.0));
+		assert_eq!(out[0], 1.0);
+
+		// Rising and falling edges, alternating from the first fall.
+		let edges: Vec<usize> = (1..out.len()).filter(|&i| out[i] != out[i - 1]).collect();
+		assert_eq!(edges.len(), 7);
+		assert!((edges[0] as isize - 2756).abs() <= 1, "first gate lasted {} samples", edges[0]);
+		for (k, pair) in edges.windows(2).enumerate() {
+			let run = (pair[1] - pair[0]) as isize;
+			let expected = if k % 2 == 0 { 8269 } else { 2756 };
+			assert!((run - expected).abs() <= 1, "run {} lasted {} samples", k, run);
+		}
+	}
+
+	#[test]
+	fn freeze_sustains_a_captured_chord() {
+		let source = "G = CreateStore(1)\nH = CreateStore(0)\n\
+			Chord = Add(Add(Sine(220, 0.2), Sine(330, 0.2)), Sine(440, 0.2))\n\
+			Output(Freeze(Mul(Chord, G), H))";
+		let play = |hold: bool| {
+			let mut loader = GraphLoader::from_source(source);
+			let mut graph = loader.load().unwrap();
+			if hold {
+				graph.schedule_store(22050, loader.store_id("H").unwrap(), 1.0);
+			}
+			graph.schedule_store(26460, loader.store_id("G").unwrap(), 0.0);
+			let out: Vec<f32> = (0..66150).map(|_| graph.sample()).collect();
+			out[44100..].to_vec()
+		};
+		let rms = |x: &[f32]| (x.iter().map(|s| s * s).sum::<f32>() / x.len() as f32).sqrt();
+		assert!(rms(&play(false)) < 1e-3);
+
+		let frozen = &play(true)[..];
+		assert!(rms(frozen) > 0.1, "frozen output died away to {}", rms(frozen));
+		let floor = [150.0, 275.0, 385.0, 600.0].iter()
+			.map(|&f| magnitude(frozen, f, 44100.0))
+			.fold(0.0, f32::max);
+		for &f in [220.0, 330.0, 440.0].iter() {
+			let m = magnitude(frozen, f, 44100.0);
+			assert!(m > floor * 10.0, "{} Hz at {} vs floor {}", f, m, floor);
+		}
+	}
+
+	#[test]
+	fn additive_partials_follow_their_amps() {
+		let out = render("Output(Additive(220, 1, 0.5, 0.25))", 44100);
+		assert!(out.iter().all(|s| s.abs() <= 1.0));
+		let fundamental = magnitude(&out, 220.0, 44100.0);
+		for &(f, amp) in [(440.0, 0.5), (660.0, 0.25)].iter() {
+			let ratio = magnitude(&out, f, 44100.0) / fundamental;
+			assert!((ratio - amp).abs() < 0.01, "{} Hz at {} of the fundamental", f, ratio);
+		}
+		assert!(magnitude(&out, 880.0, 44100.0) < fundamental * 0.001);
+		assert!(magnitude(&out, 330.0, 44100.0) < fundamental * 0.001);
+	}
+
+	#[test]
+	fn signature_table_describes_sine() {
+		let sine = function_signatures().iter().find(|sig| sig.name == "Sine").unwrap();
+		let names: Vec<&str> = sine.args.iter().map(|a| a.name).collect();
+		assert_eq!(names, vec!["freq", "amp"]);
+		assert!(sine.args.iter().all(|a| a.kind == ArgKind::Input));
+		assert_eq!((sine.min, sine.max), (2, Some(2)));
+		assert!(sine.accepts(2) && !sine.accepts(1) && !sine.accepts(3));
+	}
+
+	#[test]
+	fn truncated_call_is_an_eof_error() {
+		let e = load_error("Output(Mul(1,");
+		assert_eq!(e.message, "Unexpected end of input.");
+		assert!(e.token.is_some());
+
+		let e = load_error("Output(Mul(1, 2)");
+		assert!(e.message.starts_with("Unexpected end of input"), "{}", e.message);
+	}
+
+	#[test]
+	fn node_limit_fails_the_load() {
+		let source = "Output(Add(Sine(220, 0.2), Sine(330, 0.2)))";
+		let mut loader = GraphLoader::from_source(source);
+		loader.set_max_nodes(3);
+		let e = match loader.load() {
+			Ok(_) => panic!("four nodes loaded under a limit of three"),
+			Err(e) => e
+		};
+		assert_eq!(e.message, "Graph has more than 3 nodes.");
+
+		let mut loader = GraphLoader::from_source(source);
+		loader.set_max_nodes(4);
+		assert!(loader.load().is_ok());
+	}
+
+	#[test]
+	fn unipolar_and_bipolar_are_inverses() {
+		assert_eq!(render("Output(Unipolar(-1))", 1), vec![0.0]);
+		assert_eq!(render("Output(Unipolar(1))", 1), vec![1.0]);
+		assert_eq!(render("Output(Unipolar(0))", 1), vec![0.5]);
+		assert_eq!(render("Output(Bipolar(0))", 1), vec![-1.0]);
+		assert_eq!(render("Output(Bipolar(1))", 1), vec![1.0]);
+
+		let sine = render("Output(Sine(441, 0.8))", 100);
+		let round_trip = render("Output(Bipolar(Unipolar(Sine(441, 0.8))))", 100);
+		for (a, b) in sine.iter().zip(round_trip.iter()) {
+			assert!((a - b).abs() < 1e-6);
+		}
+	}
+
+	#[test]
+	fn reader_peek_matches_next() {
+		let mut sr = Reader::new("ab\nc".chars().collect());
+		let mut seen = Vec::new();
+		while sr.has_next() {
+			let peeked = sr.peek();
+			assert_eq!(peeked, Some(sr.current()));
+			assert_eq!(sr.next(), peeked);
+			assert_eq!(sr.prev(), peeked);
+			seen.push(peeked.unwrap());
+		}
+		assert_eq!(seen, vec!['a', 'b', '\n', 'c']);
+		assert_eq!(sr.peek(), None);
+		assert_eq!(sr.next(), None);
+		assert_eq!(sr.position(), (2, 2));
+
+		sr.step_back();
+		sr.step_back();
+		assert_eq!(sr.position(), (1, 3));
+		assert_eq!(sr.peek(), Some('\n'));
+		assert_eq!(sr.next(), Some('\n'));
+	}
+
+	#[test]
+	fn ring_mode_suppresses_the_carrier_and_am_keeps_it() {
+		let ring = render("Output(AM(Sine(1000, 0.5), Sine(100, 1), 1, 0))", 44100);
+		let am = render("Output(AM(Sine(1000, 0.5), Sine(100, 1), 1, 1))", 44100);
+		let at = |x: &[f32], f: f32| magnitude(x, f, 44100.0);
+
+		for &f in [900.0, 1100.0].iter() {
+			assert!(at(&ring, f) > at(&ring, 1000.0) * 100.0, "ring sideband {} Hz too weak", f);
+			assert!(at(&am, f) > at(&am, 1000.0) * 0.2, "AM sideband {} Hz too weak", f);
+		}
+		assert!(at(&am, 1000.0) > at(&ring, 1000.0) * 100.0);
+		assert!(at(&am, 1000.0) > at(&am, 900.0));
+	}
+
+	#[test]
+	fn tilt_trades_lows_for_highs() {
+		let tilted = |amount: &str| {
+			let out = render(&format!("Output(Tilt(Add(Sine(100, 0.2), Sine(5000, 0.2)), {}))", amount), 44100);
+			let window = &out[4410..];
+			(magnitude(window, 100.0, 44100.0), magnitude(window, 5000.0, 44100.0))
+		};
+		let (dry_low, dry_high) = tilted("0");
+		let (bright_low, bright_high) = tilted("1");
+		let (dark_low, dark_high) = tilted("-1");
+
+		assert!((dry_high / dry_low - 1.0).abs() < 0.05);
+		assert!(bright_high > dry_high * 1.5 && bright_low < dry_low);
+		assert!(dark_high < dry_high / 1.5 && dark_low > dry_low);
+	}
+
+	fn lexed(input: &str) -> Vec<(TokenType, f32)> {
+		lex(input).unwrap().iter().map(|t| (t.token_type, t.value)).collect()
+	}
+
+	#[test]
+	fn minus_is_only_a_sign_where_a_value_starts() {
+		use self::TokenType::*;
+		assert_eq!(lexed("-0.5"), vec![(Number, -0.5), (EOF, 0.0)]);
+		assert_eq!(lexed("10-5"), vec![(Number, 10.0), (Minus, 0.0), (Number, 5.0), (EOF, 0.0)]);
+		assert_eq!(lexed("10 - -5"), vec![(Number, 10.0), (Minus, 0.0), (Number, -5.0), (EOF, 0.0)]);
+		assert_eq!(render("Output(10-5 - 4.5)", 1), vec![0.5]);
+
+		let e = match lex("Sine(1.2.3, 1)") {
+			Ok(tokens) => panic!("lexed {:?}", tokens),
+			Err(e) => e
+		};
+		assert_eq!(e.message, "Invalid number.");
+		let token = e.token.unwrap();
+		assert_eq!((token.lexeme.as_str(), token.line, token.col), ("1.2.3", 1, 6));
+	}
+
+	// Magnitude-weighted mean frequency of a Hann-windowed frame.
+	fn centroid(frame: &[f32], sample_rate: f32) -> f32 {
+		let n = frame.len();
+		let (mut weighted, mut total) = (0.0, 0.0);
+		for bin in 1..n / 2 {
+			let (mut re, mut im) = (0.0, 0.0);
+			for (i, s) in frame.iter().enumerate() {
+				let w = 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos();
+				let a = 2.0 * PI * (bin * i) as f32 / n as f32;
+				re += s * w * a.cos();
+				im -= s * w * a.sin();
+			}
+			let m = (re * re + im * im).sqrt();
+			weighted += m * bin as f32 * sample_rate / n as f32;
+			total += m;
+		}
+		weighted / total
+	}
+
+	#[test]
+	fn auto_wah_opens_on_the_attack_and_closes_in_the_decay() {
+		// A saw struck at full level and left to decay.
+		let source = "E = CreateStore(1, 200)\nWriter(E, 0)\nOutput(AutoWah(Mul(Saw(110, 0.8), E), 2, 300))";
+		let out = render(source, 44100);
+
+		let at = |ms: usize| centroid(&out[ms * 441 / 10..ms * 441 / 10 + 512], 44100.0);
+		let (attack, open, decay) = (at(0), at(30), at(600));
+		assert!(open > attack * 1.5, "attack {} Hz, open {} Hz", attack, open);
+		assert!(open > decay * 3.0, "open {} Hz, decay {} Hz", open, decay);
+	}
+
+	#[test]
+	fn saw_and_square_span_one_cycle_symmetrically() {
+		// 441 Hz is exactly 100 samples per cycle.
+		let saw = render("Output(Saw(441, 1))", 1000);
+		let (lo, hi) = saw.iter().fold((1.0f32, -1.0f32), |(lo, hi), &s| (lo.min(s), hi.max(s)));
+		assert!((-1.0..-0.97).contains(&lo), "saw bottoms out at {}", lo);
+		assert!(hi <= 1.0 && hi > 0.97, "saw tops out at {}", hi);
+		let rises = saw.windows(2).filter(|w| w[1] > w[0]).count();
+		assert!(rises >= 989, "saw only rose {} times", rises);
+
+		let square = render("Output(Square(441, 1))", 1000);
+		assert!(square.iter().all(|&s| s == 1.0 || s == -1.0));
+		let high = square.iter().filter(|&&s| s == 1.0).count();
+		assert!((high as isize - 500).abs() <= 10, "high for {} of 1000 samples", high);
+		for cycle in square.chunks(100).skip(1) {
+			let high = cycle.iter().filter(|&&s| s == 1.0).count();
+			assert!((high as isize - 50).abs() <= 1, "high for {} of 100 samples", high);
+		}
+	}
+
+	#[test]
+	fn second_output_is_rejected() {
+		let e = load_error("Output(Sine(440, 0.5))\nOutput(Saw(220, 0.5))");
+		assert_eq!(e.message, "Output already declared (node 1)");
+		assert_eq!(e.token.map(|t| t.line), Some(2));
+		let e = load_error("Output(0.5)\nStereoOutput(0.1, 0.2)");
+		assert_eq!(e.message, "Output already declared (node 0)");
+
+		// Lenient loads keep the first output playing.
+		let mut loader = GraphLoader::from_source("Output(0.5)\nOutput(0.25)");
+		let (mut graph, errors) = loader.load_lenient().unwrap();
+		assert_eq!(errors.len(), 1);
+		assert_eq!(graph.sample(), 0.5);
+	}
+
+	#[test]
+	fn map_handles_zero_width_and_clamps_overshoot() {
+		let flat = render("Output(Map(Sine(5, 1), 0.2, 0.2, 0.3, 0.9))", 4410);
+		assert!(flat.iter().all(|&s| s == 0.3), "zero-width range should give to_min");
+
+		// The sine swings to twice the source range on both sides.
+		let loose = render("Output(Map(Sine(5, 1), -0.5, 0.5, -0.25, 0.25))", 8820);
+		let clamped = render("Output(MapClamped(Sine(5, 1), -0.5, 0.5, -0.25, 0.25))", 8820);
+		let inverted = render("Output(MapClamped(Sine(5, 1), -0.5, 0.5, 0.25, -0.25))", 8820);
+		let peak = |out: &[f32]| out.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+		assert!(peak(&loose) > 0.45, "unclamped peak {}", peak(&loose));
+		for out in [&clamped, &inverted].iter() {
+			assert!(out.iter().all(|s| s.is_finite() && s.abs() <= 0.25));
+			assert!(peak(out) > 0.249);
+		}
+	}
+
+
+	#[test]
+	fn band_limited_oscillators_alias_less() {
+		// Energy in the top half of the spectrum, by a plain DFT over whole
+		// cycles, skipping the real harmonics so only folded-back aliases
+		// count. Bins are 20 Hz apart, so 5 kHz harmonics land every 250.
+		let upper = |out: &[f32]| -> f32 {
+			let n = out.len();
+			(n / 4..n / 2).filter(|k| k % 250 != 0).map(|k| {
+				let (mut re, mut im) = (0.0f32, 0.0f32);
+				for (i, &x) in out.iter().enumerate() {
+					let w = 2.0 * PI * ((k * i) % n) as f32 / n as f32;
+					re += x * w.cos();
+					im -= x * w.sin();
+				}
+				re * re + im * im
+			}).sum()
+		};
+		for &(naive, bl) in [("Saw", "SawBL"), ("Square", "SquareBL")].iter() {
+			let a = upper(&render(&format!("Output({}(5000, 0.5))", naive), 4410)[2205..]);
+			let b = upper(&render(&format!("Output({}(5000, 0.5))", bl), 4410)[2205..]);
+			assert!(b < a * 0.1, "{} upper energy {} vs naive {}", bl, b, a);
+		}
+	}
+
+
+	#[test]
+	fn lfo_is_unipolar_and_lfo_bi_is_symmetric() {
+		let range = |out: &[f32]| out.iter().fold((f32::MAX, f32::MIN), |(lo, hi), &s| (lo.min(s), hi.max(s)));
+
+		let (lo, hi) = range(&render("Output(LFO(5))", 44100));
+		assert!((0.0..0.001).contains(&lo) && hi <= 1.0 && hi > 0.999, "LFO spans {}..{}", lo, hi);
+
+		let bi = render("Output(LFOBi(5, 0.8))", 44100);
+		let (lo, hi) = range(&bi);
+		assert!((lo + 0.8).abs() < 0.001 && (hi - 0.8).abs() < 0.001, "LFOBi spans {}..{}", lo, hi);
+		let mean = bi.iter().sum::<f32>() / bi.len() as f32;
+		assert!(mean.abs() < 0.001, "LFOBi mean {}", mean);
+
+		// Depth taken from another LFO still swings evenly about zero.
+		let (lo, hi) = range(&render("Output(LFOBi(40, LFO(2)))", 44100));
+		assert!((lo + hi).abs() < 0.01 && hi > 0.99, "modulated LFOBi spans {}..{}", lo, hi);
+	}
+}
@@ -0,0 +1,249 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+
+use crate::node::NodeGraph;
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+	u16::from(data[at]) | (u16::from(data[at + 1]) << 8)
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+	u32::from(read_u16(data, at)) | (u32::from(read_u16(data, at + 2)) << 16)
+}
+
+/// Loads a PCM (8/16/24/32-bit) or 32-bit float WAV file, averaging all
+/// channels down to mono.
+pub fn load_mono(path: &str) -> Result<Vec<f32>, String> {
+	let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+	if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+		return Err(format!("{}: Not a WAV file.", path));
+	}
+
+	let mut format = None;
+	let mut samples = None;
+	let mut pos = 12;
+	while pos + 8 <= data.len() {
+		let id = &data[pos..pos + 4];
+		let size = read_u32(&data, pos + 4) as usize;
+		let body = pos + 8;
+		let end = (body + size).min(data.len());
+		match id {
+			b"fmt " if size >= 16 => {
+				if body + 16 > data.len() {
+					return Err(format!("{}: Truncated \"fmt\" chunk.", path));
+				}
+				format = Some((
+					read_u16(&data, body),
+					read_u16(&data, body + 2) as usize,
+					read_u16(&data, body + 14) as usize
+				));
+			},
+			b"data" => samples = Some(&data[body..end]),
+			_ => {}
+		}
+		pos = body + size + (size & 1);
+	}
+
+	let (tag, channels, bits) = match format {
+		Some(f) => f,
+		None => return Err(format!("{}: Missing \"fmt\" chunk.", path))
+	};
+	let bytes = match samples {
+		Some(b) => b,
+		None => return Err(format!("{}: Missing \"data\" chunk.", path))
+	};
+	if channels == 0 {
+		return Err(format!("{}: Invalid channel count.", path));
+	}
+
+	let width = bits / 8;
+	let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+		(1, 8) => |b| (f32::from(b[0]) - 128.0) / 128.0,
+		(1, 16) => |b| f32::from(i16::from_le_bytes([b[0], b[1]])) / 32768.0,
+		(1, 24) => |b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0,
+		(1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0,
+		(3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+		_ => return Err(format!("{}: Unsupported WAV format ({}, {} bits).", path, tag, bits))
+	};
+
+	let frame = width * channels;
+	let mono = bytes.chunks_exact(frame)
+		.map(|f| f.chunks_exact(width).map(decode).sum::<f32>() / channels as f32)
+		.collect();
+	Ok(mono)
+}
+
+const LIMIT_LOOKAHEAD_MS: f32 = 2.0;
+const LIMIT_RELEASE_MS: f32 = 100.0;
+const TRUE_PEAK_TAPS: isize = 8;
+const TRUE_PEAK_OVERSAMPLE: usize = 8;
+
+// Largest of sample `i` and the points between it and the next, found by
+// oversampling with a Hann-windowed sinc. These are the peaks a DAC
+// reconstructs even when every sample is under the ceiling.
+fn true_peak(x: &[f32], i: usize) -> f32 {
+	let mut peak = x[i].abs();
+	for step in 1..TRUE_PEAK_OVERSAMPLE {
+		let t = step as f32 / TRUE_PEAK_OVERSAMPLE as f32;
+		let mut y = 0.0;
+		for k in (1 - TRUE_PEAK_TAPS)..=TRUE_PEAK_TAPS {
+			let j = i as isize + k;
+			if j < 0 || j as usize >= x.len() {
+				continue;
+			}
+			let d = t - k as f32;
+			let window = 0.5 + 0.5 * (PI * d / TRUE_PEAK_TAPS as f32).cos();
+			y += x[j as usize] * (PI * d).sin() / (PI * d) * window;
+		}
+		peak = peak.max(y.abs());
+	}
+	peak
+}
+
+/// Lookahead brickwall over whole channels, linked so the stereo image
+/// holds. The gain is the minimum needed over the next few milliseconds,
+/// released slowly and then averaged over the lookahead, which ramps it
+/// down ahead of each peak without ever rising above what that peak needs.
+fn brickwall(channels: &mut [Vec<f32>], ceiling: f32, sample_rate: u32) {
+	let len = channels[0].len();
+	let lookahead = ((LIMIT_LOOKAHEAD_MS / 1000.0 * sample_rate as f32) as usize).max(1);
+	let release = 1.0 - (-1000.0 / (LIMIT_RELEASE_MS * sample_rate as f32)).exp();
+
+	// Needed gain, padded with unity on both sides by `lookahead` frames.
+	let needed = |q: usize| -> f32 {
+		if q < lookahead || q >= len + lookahead {
+			return 1.0;
+		}
+		let peak = channels.iter().map(|c| true_peak(c, q - lookahead)).fold(0.0, f32::max);
+		if peak > ceiling { ceiling / peak } else { 1.0 }
+	};
+	let needed: Vec<f32> = (0..len + lookahead * 2).map(needed).collect();
+
+	let mut held = Vec::with_capacity(len + lookahead);
+	let mut window: VecDeque<usize> = VecDeque::new();
+	let mut gain = 1.0;
+	for q in 0..needed.len() {
+		while window.back().map_or(false, |&b| needed[b] >= needed[q]) {
+			window.pop_back();
+		}
+		window.push_back(q);
+		if q < lookahead {
+			continue;
+		}
+		while window.front().map_or(false, |&f| f < q - lookahead) {
+			window.pop_front();
+		}
+		gain = needed[window[0]].min(gain + (1.0 - gain) * release);
+		held.push(gain);
+	}
+
+	let mut sum: f64 = held[..=lookahead].iter().map(|g| f64::from(*g)).sum();
+	for n in 0..len {
+		let gain = (sum / (lookahead + 1) as f64) as f32;
+		for c in channels.iter_mut() {
+			c[n] = (c[n] * gain).clamp(-ceiling, ceiling);
+		}
+		if n + lookahead + 1 < held.len() {
+			sum += f64::from(held[n + lookahead + 1]) - f64::from(held[n]);
+		}
+	}
+}
+
+/// Renders `seconds` of `graph` to a 16-bit PCM WAV file, in stereo when
+/// the graph has a `StereoOutput` node. With a `ceiling_db` the render is
+/// run through a true-peak brickwall so it never exceeds that level.
+pub fn render_to_wav(graph: &mut NodeGraph, seconds: f32, sample_rate: u32, path: &Path, ceiling_db: Option<f32>) -> Result<(), String> {
+	let frames = (seconds.max(0.0) * sample_rate as f32).round() as usize;
+	let channels: u16 = if graph.is_stereo() { 2 } else { 1 };
+	let block_align = channels * 2;
+	let data_len = (frames * block_align as usize) as u32;
+
+	// The limiter needs the unclipped signal, or every peak over 0 dBFS
+	// reaches it already flattened.
+	let master_clip = graph.master_clip();
+	if ceiling_db.is_some() {
+		graph.set_master_clip(false);
+	}
+	let scrub = |s: f32| if s.is_nan() { 0.0 } else { s };
+	let mut rendered = vec![Vec::with_capacity(frames); channels as usize];
+	for _ in 0..frames {
+		if channels == 2 {
+			let (left, right) = graph.sample_stereo();
+			rendered[0].push(scrub(left));
+			rendered[1].push(scrub(right));
+		} else {
+			rendered[0].push(scrub(graph.sample()));
+		}
+	}
+	graph.set_master_clip(master_clip);
+	if let Some(db) = ceiling_db {
+		if frames > 0 {
+			brickwall(&mut rendered, 10.0f32.powf(db / 20.0), sample_rate);
+		}
+	}
+
+	let mut out = Vec::with_capacity(44 + data_len as usize);
+	out.extend_from_slice(b"RIFF");
+	out.extend_from_slice(&(36 + data_len).to_le_bytes());
+	out.extend_from_slice(b"WAVEfmt ");
+	out.extend_from_slice(&16u32.to_le_bytes());
+	out.extend_from_slice(&1u16.to_le_bytes());
+	out.extend_from_slice(&channels.to_le_bytes());
+	out.extend_from_slice(&sample_rate.to_le_bytes());
+	out.extend_from_slice(&(sample_rate * u32::from(block_align)).to_le_bytes());
+	out.extend_from_slice(&block_align.to_le_bytes());
+	out.extend_from_slice(&16u16.to_le_bytes());
+	out.extend_from_slice(b"data");
+	out.extend_from_slice(&data_len.to_le_bytes());
+
+	let pcm = |s: f32| ((s.clamp(-1.0, 1.0) * 32767.0).round() as i16).to_le_bytes();
+	for n in 0..frames {
+		for c in rendered.iter() {
+			out.extend_from_slice(&pcm(c[n]));
+		}
+	}
+	fs::write(path, out).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::parser::GraphLoader;
+	use std::env;
+	use std::process;
+
+	#[test]
+	fn ceiling_limits_a_hot_render() {
+		let mut graph = GraphLoader::from_source("Output(Sine(441, 4))").load().unwrap();
+		let path = env::temp_dir().join(format!("twen-ceiling-{}.wav", process::id()));
+		render_to_wav(&mut graph, 1.0, 44100, &path, Some(-1.0)).unwrap();
+		let out = load_mono(path.to_str().unwrap()).unwrap();
+		fs::remove_file(&path).unwrap();
+
+		let ceiling = 10.0f32.powf(-1.0 / 20.0);
+		let lsb = 1.0 / 32768.0;
+		let peak = out.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+		assert!(peak <= ceiling + lsb, "peak {} over ceiling {}", peak, ceiling);
+		assert!(peak > ceiling * 0.9, "limiter pulled the peak down to {}", peak);
+		let true_peak = (0..out.len()).map(|i| true_peak(&out, i)).fold(0.0, f32::max);
+		assert!(true_peak <= ceiling * 1.01, "true peak {} over ceiling {}", true_peak, ceiling);
+	}
+
+	#[test]
+	fn ceiling_limits_instead_of_clipping() {
+		let mut graph = GraphLoader::from_source("Output(Sine(441, 4))").load().unwrap();
+		let path = env::temp_dir().join(format!("twen-flat-{}.wav", process::id()));
+		render_to_wav(&mut graph, 1.0, 44100, &path, Some(-1.0)).unwrap();
+		let out = load_mono(path.to_str().unwrap()).unwrap();
+		fs::remove_file(&path).unwrap();
+
+		// A limited sine only dwells near its peak briefly; a clipped one
+		// sits flat there for most of each cycle.
+		let peak = out.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+		let flat = out.iter().filter(|s| s.abs() >= peak * 0.999).count();
+		assert!(flat * 20 < out.len(), "{} of {} samples are flat-topped", flat, out.len());
+		assert!(graph.master_clip());
+	}
+}